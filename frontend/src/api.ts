@@ -2,12 +2,14 @@ import { Config } from './config';
 import { Subscription } from './subscription';
 
 export type MessageResult = {endpoint_domain: string, result_message: string, result_status: string, subscription: string}
-export type Message = { channelId: string, message: string, time: string, result?: MessageResult[]};
+export type Message = { channelId: string, message: string, title?: string, body?: string, priority?: string, status?: string, deliver_at?: string, time: string, result?: MessageResult[]};
 export type ChannelResponse = {
     channelId: string,
     pubKey: string,
     messages?: Message[],
     subscriptions: string[],
+    name?: string,
+    description?: string,
     error?: string,
 };
 
@@ -16,6 +18,8 @@ export namespace NotifyAPI {
         return fetch(Config.API_SERVER + path, requestInit).then((c) => {
             if (c.ok) {
                 return c.json()
+            } else if (c.status == 403) {
+                throw new Error("This channel needs a token to be read.")
             } else if (c.status == 404) {
                 throw new Error("Not found.")
             } else {
@@ -24,11 +28,15 @@ export namespace NotifyAPI {
         });
     }
 
+    function withToken(path: string, token?: string): string {
+        return token ? `${path}?token=${encodeURIComponent(token)}` : path;
+    }
+
     export function registerChannel(): Promise<ChannelResponse> {
         return request('/api/register_channel', { method: 'POST' });
     }
 
-    export function subscribe(channelId: string, subscription: Subscription): Promise<any> {
+    export function subscribe(channelId: string, subscription: Subscription, token?: string): Promise<any> {
         let req = {
             method: 'POST',
             body: JSON.stringify(subscription),
@@ -37,11 +45,15 @@ export namespace NotifyAPI {
             })
         }
 
-        return request(`/${channelId}/subscribe`, req);
+        return request(withToken(`/${channelId}/subscribe`, token), req);
+    }
+
+    export function unsubscribe(channelId: string, subscriptionId: string, token?: string): Promise<any> {
+        return request(withToken(`/${channelId}/subscribe/${subscriptionId}`, token), { method: 'DELETE' });
     }
 
-    export function fetchChannel(channelId: string): Promise<ChannelResponse> {
-        return request(`/${channelId}/json`);
+    export function fetchChannel(channelId: string, token?: string): Promise<ChannelResponse> {
+        return request(withToken(`/${channelId}/json`, token));
     }
 
     export function getURLOfQR(channelId: string): string {
@@ -9,6 +9,13 @@ interface MessageListProps {
     messages: Message[],
 }
 
+const PRIORITY_COLORS: { [priority: string]: string } = {
+    min: 'grey',
+    low: 'blue',
+    high: 'orange',
+    urgent: 'red',
+};
+
 class MessageList extends React.Component<MessageListProps, {}> {
     render() {
         if (this.props.messages.length == 0) {
@@ -22,9 +29,25 @@ class MessageList extends React.Component<MessageListProps, {}> {
                         <div className="event" key={i}>
                             <div className="content">
                                 <div className="summary">
+                                    {
+                                        message.priority && PRIORITY_COLORS[message.priority] ?
+                                        <div className={'ui mini label ' + PRIORITY_COLORS[message.priority]}>{message.priority}</div>
+                                        : null
+                                    }
                                     <samp>{message.message}</samp>
                                     <div className="date">{message.time}</div>
                                 </div>
+                                {
+                                    message.status == 'pending' ?
+                                    <div className="meta"><i className="clock icon"></i>Scheduled for {message.deliver_at}</div>
+                                    : message.status == 'queued' ?
+                                    <div className="meta"><i className="hourglass half icon"></i>Queued for delivery</div>
+                                    : message.status == 'cancelled' ?
+                                    <div className="meta"><i className="ban icon grey"></i>Cancelled</div>
+                                    : message.status == 'failed' ?
+                                    <div className="meta"><i className="exclamation circle icon red"></i>Could not be delivered</div>
+                                    : null
+                                }
                                 {
                                     message.result ? message.result.map((result) =>
                                         <div className="meta">
@@ -45,10 +68,13 @@ class MessageList extends React.Component<MessageListProps, {}> {
 
 interface ChannelPageProps {
     channelId: string,
+    token?: string,
 }
 
 interface ChannelPageState {
     messages: Message[],
+    name?: string,
+    description?: string,
     loading: boolean,
     subscribed: boolean,
     subscribeDisabled: boolean,
@@ -70,7 +96,7 @@ export class ChannelPage extends React.Component<ChannelPageProps, ChannelPageSt
     }
 
     loadChannel() {
-        NotifyAPI.fetchChannel(this.props.channelId).then((response) => {
+        NotifyAPI.fetchChannel(this.props.channelId, this.props.token).then((response) => {
             if (response.error) {
                 if (this.state.loading) {
                     this.setState({
@@ -98,6 +124,8 @@ export class ChannelPage extends React.Component<ChannelPageProps, ChannelPageSt
 
             this.setState({
                 messages: response.messages || [],
+                name: response.name,
+                description: response.description,
                 loading: false,
             });
         }).catch((reason) => {
@@ -119,7 +147,7 @@ export class ChannelPage extends React.Component<ChannelPageProps, ChannelPageSt
     }
 
     onSubscribe() {
-        this.subscriptionManager.subscribe(this.props.channelId).then(() => {
+        this.subscriptionManager.subscribe(this.props.channelId, this.props.token).then(() => {
             this.setState({
                 subscribed: true,
             })
@@ -128,6 +156,16 @@ export class ChannelPage extends React.Component<ChannelPageProps, ChannelPageSt
         });
     }
 
+    onUnsubscribe() {
+        this.subscriptionManager.unsubscribe(this.props.channelId, this.props.token).then(() => {
+            this.setState({
+                subscribed: false,
+            })
+        }).catch((e: Error) => {
+            console.log(e);
+        });
+    }
+
     render() {
         if (this.state.loading) {
             return <div className="ui active centered inline loader"></div>;
@@ -138,7 +176,10 @@ export class ChannelPage extends React.Component<ChannelPageProps, ChannelPageSt
         let channelEndpoint = `${Config.API_SERVER}/${this.props.channelId}`
         let webLink = `${Config.WEB_SERVER}/c/${this.props.channelId}`;
         return <div>
-            <h2>Channel <samp>{this.props.channelId}</samp></h2>
+            {this.state.name ?
+                <h2>{this.state.name} <small><samp>{this.props.channelId}</samp></small></h2> :
+                <h2>Channel <samp>{this.props.channelId}</samp></h2>}
+            {this.state.description && <p>{this.state.description}</p>}
             <h3>Recent messages</h3>
             <MessageList messages={this.state.messages} />
 
@@ -155,8 +196,8 @@ export class ChannelPage extends React.Component<ChannelPageProps, ChannelPageSt
                     <button
                         className="ui disabled button">Can’t access service worker, maybe you disabled notifications?</button> :
                     (this.state.subscribed ?
-                        <button
-                            className="ui disabled button">Already Subscribed</button> :
+                        <button onClick={this.onUnsubscribe.bind(this)}
+                            className="ui button">Unsubscribe this device</button> :
                         <button onClick={this.onSubscribe.bind(this)}
                             className="ui primary button">Subscribe on this device</button>
                     )
@@ -16,9 +16,10 @@ window.addEventListener('load', () => {
     let match = document.location.pathname.match('/c/([A-Za-z0-9]+)/?');
     if (match) {
         let channelId = match[1];
+        let token = new URLSearchParams(document.location.search).get('token') || undefined;
         let channelContainer = document.getElementById('channel');
         ReactDOM.render(
-            <ChannelPage channelId={channelId} />,
+            <ChannelPage channelId={channelId} token={token} />,
             channelContainer
         );
     }
@@ -1,4 +1,5 @@
 import { NotifyAPI } from './api';
+import { Config } from './config';
 import { urlB64ToUint8Array } from './util';
 
 type Keys = { p256dh: string, auth: string };
@@ -51,7 +52,7 @@ export class SubscriptionManager {
         return (Notification as any).permission !== 'denied';
     }
 
-    subscribe(channelId: string): Promise<void> {
+    subscribe(channelId: string, token?: string): Promise<void> {
         return navigator.serviceWorker.register('/static/service-worker.js')
             .then((registration) => {
                 const subscribeOptions = {
@@ -62,7 +63,28 @@ export class SubscriptionManager {
             })
             .then(Subscription.fromPushSubscriptionAsync)
             .then((pushSubscription) => {
-                NotifyAPI.subscribe(channelId, pushSubscription);
+                NotifyAPI.subscribe(channelId, pushSubscription, token);
+                return this.tellServiceWorker({
+                    type: 'subscribed',
+                    channelId: channelId,
+                    apiServer: Config.API_SERVER,
+                    token: token,
+                });
             });
     }
+
+    unsubscribe(channelId: string, token?: string): Promise<void> {
+        // The browser's push subscription is shared by every channel it subscribes to,
+        // so it is only removed from this channel, not unsubscribed.
+        return this.getSubscription()
+            .then((subscription) => NotifyAPI.unsubscribe(channelId, subscription.id, token))
+            .then(() => this.tellServiceWorker({ type: 'unsubscribed', channelId: channelId }));
+    }
+
+    // The service worker keeps track of subscribed channels, so that it can renew their
+    // subscriptions if the push service replaces the browser's push subscription.
+    private tellServiceWorker(message: {}): Promise<void> {
+        return navigator.serviceWorker.ready
+            .then((registration) => registration.active!.postMessage(message));
+    }
 }
\ No newline at end of file
@@ -1,30 +1,133 @@
+// Vibration pattern (in milliseconds) for messages that ask to vibrate.
+const VIBRATE_PATTERN = [200, 100, 200];
+
+// The vibration pattern for a notification. showNotification throws if a silent notification
+// has one at all, even an empty one.
+function vibratePattern(data) {
+    if (data.silent || data.vibrate === undefined || data.vibrate === null) {
+        return undefined;
+    }
+    return data.vibrate ? VIBRATE_PATTERN : [];
+}
+
 self.addEventListener('notificationclick', function (event) {
-    if (event.notification.data.action) {
-        clients.openWindow(event.notification.data.action);
+    let data = event.notification.data;
+    // Action buttons are identified by their index in data.actions.
+    let url = event.action ? data.actions[event.action].url : data.action;
+
+    if (url) {
+        clients.openWindow(url);
     }
 });
 
 self.addEventListener('push', function (event) {
     let data = event.data.json();
 
-    let title_body = data.message.split('\n');
-    let title = title_body.shift();
-    let body = title_body.join('\n');
+    let title, body;
+    if (data.title !== undefined) {
+        title = data.title;
+        body = data.body;
+    } else {
+        // Senders that don't give a title put it on the first line of the message.
+        let title_body = data.message.split('\n');
+        title = title_body.shift();
+        body = title_body.join('\n');
+    }
 
     let options = {
         body: body,
-        icon: '/static/icon.png',
-        //tag: data.channel,
+        icon: data.icon || '/static/icon.png',
+        image: data.image,
+        badge: data.badge,
+        // Tags are shared by every channel on the device, so scope them to this one.
+        tag: data.tag && (data.channel + ':' + data.tag),
         data: data.data,
-        //renotify: true,
-        vibrate: data.vibrate,
+        renotify: data.renotify || false,
+        actions: (data.data.actions || []).map(function (action, index) {
+            return {action: String(index), title: action.label};
+        }),
+        requireInteraction: data.priority === 'urgent',
+        vibrate: vibratePattern(data),
         silent: data.silent
     };
 
-    console.log(JSON.stringify(event.data.json()))
-    console.log(JSON.stringify(options))
-
     const promiseChain = self.registration.showNotification(title, options);
 
     event.waitUntil(promiseChain);
-});
\ No newline at end of file
+});
+
+// Channels this browser is subscribed to, kept so that their subscriptions can be renewed
+// when the push service replaces the browser's push subscription. Maps each channel ID to
+// the API server it is on and its read token, if it has one.
+const CHANNELS_CACHE = 'notify-run-channels';
+const CHANNELS_KEY = '/channels.json';
+
+function loadChannels() {
+    return caches.open(CHANNELS_CACHE)
+        .then(function (cache) { return cache.match(CHANNELS_KEY); })
+        .then(function (response) { return response ? response.json() : {}; });
+}
+
+function saveChannels(channels) {
+    return caches.open(CHANNELS_CACHE).then(function (cache) {
+        return cache.put(CHANNELS_KEY, new Response(JSON.stringify(channels)));
+    });
+}
+
+// The ID a page gives a push subscription when subscribing: the SHA-1 of its endpoint.
+function subscriptionId(endpoint) {
+    return crypto.subtle.digest('SHA-1', new TextEncoder().encode(endpoint)).then(function (digest) {
+        return Array.from(new Uint8Array(digest))
+            .map(function (b) { return ('00' + b.toString(16)).slice(-2); })
+            .join('');
+    });
+}
+
+// Pages tell us which channels they subscribe to and unsubscribe from.
+self.addEventListener('message', function (event) {
+    let data = event.data;
+
+    event.waitUntil(loadChannels().then(function (channels) {
+        if (data.type === 'subscribed') {
+            channels[data.channelId] = {apiServer: data.apiServer, token: data.token};
+        } else if (data.type === 'unsubscribed') {
+            delete channels[data.channelId];
+        }
+        return saveChannels(channels);
+    }));
+});
+
+// When the push service replaces the browser's push subscription, send the new one to each
+// channel under the old subscription's ID, so that it replaces the old one rather than
+// being added alongside it.
+self.addEventListener('pushsubscriptionchange', function (event) {
+    let oldSubscription = event.oldSubscription;
+    if (!oldSubscription && !event.newSubscription) {
+        // Without either, there are no options to subscribe again with.
+        return;
+    }
+
+    let newSubscription = event.newSubscription ?
+        Promise.resolve(event.newSubscription)
+        : self.registration.pushManager.subscribe(oldSubscription.options);
+
+    event.waitUntil(newSubscription.then(function (subscription) {
+        let id = subscriptionId((oldSubscription || subscription).endpoint);
+
+        return Promise.all([id, loadChannels()]).then(function ([id, channels]) {
+            return Promise.all(Object.keys(channels).map(function (channelId) {
+                let channel = channels[channelId];
+                let url = channel.apiServer + '/' + channelId + '/subscribe';
+                if (channel.token) {
+                    url += '?token=' + encodeURIComponent(channel.token);
+                }
+
+                return fetch(url, {
+                    method: 'POST',
+                    body: JSON.stringify({id: id, subscription: subscription}),
+                    headers: {'Content-Type': 'application/json'},
+                });
+            }));
+        });
+    }));
+});
@@ -0,0 +1,100 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use axum::extract::{FromRequest, RequestParts};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::convert::Infallible;
+
+/// Length of generated send and read tokens.
+const TOKEN_LENGTH: usize = 32;
+
+/// Generate a random token, to be given to the channel's creator and stored hashed.
+pub fn generate_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// Hash a token for storage. Tokens are random and long, so a plain (unsalted) hash is
+/// enough to keep them from being recovered from a copy of the database.
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+/// The token given with a request, either as an `Authorization: Bearer` header or as a
+/// `token` query parameter, for clients that can't set headers.
+/// Handlers must extract it before any `HeaderMap`, which takes the request's headers.
+pub struct RequestToken(Option<String>);
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for RequestToken {
+    type Rejection = Infallible;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Infallible> {
+        let bearer = req
+            .headers()
+            .and_then(|headers| headers.get("authorization"))
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| token.trim().to_string());
+
+        let token = bearer.or_else(|| {
+            req.uri()
+                .query()
+                .and_then(|query| serde_urlencoded::from_str::<TokenQuery>(query).ok())
+                .and_then(|query| query.token)
+        });
+
+        Ok(RequestToken(token))
+    }
+}
+
+impl RequestToken {
+    /// Check the token against the hash stored for a channel. Channels without a stored
+    /// hash are not protected, so any request (with or without a token) is allowed.
+    pub fn authorize(&self, token_hash: Option<&str>) -> Result<()> {
+        let token_hash = match token_hash {
+            Some(token_hash) => token_hash,
+            None => return Ok(()),
+        };
+
+        match &self.0 {
+            Some(token) if hash_token(token) == token_hash => Ok(()),
+            Some(_) => Err(anyhow!("Invalid channel token.")),
+            None => Err(anyhow!("Missing channel token.")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_authorize() {
+        let token = generate_token();
+        let token_hash = hash_token(&token);
+        assert_eq!(64, token_hash.len());
+
+        assert!(RequestToken(None).authorize(None).is_ok());
+        assert!(RequestToken(Some("anything".to_string()))
+            .authorize(None)
+            .is_ok());
+
+        assert!(RequestToken(Some(token))
+            .authorize(Some(&token_hash))
+            .is_ok());
+        assert!(RequestToken(Some(generate_token()))
+            .authorize(Some(&token_hash))
+            .is_err());
+        assert!(RequestToken(None).authorize(Some(&token_hash)).is_err());
+    }
+}
@@ -0,0 +1,454 @@
+use super::{NamedRecord, NotifyDatabase};
+use crate::get_creds_and_project;
+use crate::model::{
+    Channel, Deactivation, Heartbeat, IdempotencyRecord, Message, ScheduledMessage, Subscription,
+    CHANNELS_COLLECTION, HEARTBEATS_COLLECTION, IDEMPOTENCY_KEYS_COLLECTION, MESSAGES_COLLECTION,
+    SCHEDULED_MESSAGES_COLLECTION, SUBSCRIPTIONS_COLLECTION,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use deadpool::managed::{self, Object, Pool};
+use futures::StreamExt;
+use serde::{de::DeserializeOwned, Serialize};
+use std::convert::Infallible;
+use tiny_firestore_odm::{Collection, Database};
+
+/// Page size used when streaming through all documents in a collection.
+const LIST_PAGE_SIZE: u32 = 100;
+
+pub struct FirestoreDatabaseManager;
+
+#[async_trait]
+impl managed::Manager for FirestoreDatabaseManager {
+    type Type = Database;
+    type Error = Infallible;
+
+    async fn create(&self) -> Result<Database, Infallible> {
+        let (token_source, project_id) = get_creds_and_project().await;
+        let db = Database::new(token_source, &project_id).await;
+
+        Ok(db)
+    }
+
+    async fn recycle(&self, _: &mut Database) -> managed::RecycleResult<Infallible> {
+        Ok(())
+    }
+}
+
+/// Storage backed by Google Firestore.
+pub struct FirestoreDatabase {
+    pool: Pool<FirestoreDatabaseManager>,
+}
+
+impl FirestoreDatabase {
+    pub fn new() -> Self {
+        let pool = Pool::<FirestoreDatabaseManager>::builder(FirestoreDatabaseManager)
+            .build()
+            .unwrap();
+
+        FirestoreDatabase { pool }
+    }
+
+    async fn db(&self) -> Result<Object<FirestoreDatabaseManager>> {
+        Ok(self.pool.get().await?)
+    }
+
+    fn channels(db: &Database) -> Collection<Channel> {
+        db.collection(CHANNELS_COLLECTION)
+    }
+
+    fn idempotency_keys(db: &Database, channel_id: &str) -> Collection<IdempotencyRecord> {
+        Self::channels(db).subcollection(channel_id, IDEMPOTENCY_KEYS_COLLECTION)
+    }
+
+    /// Scheduled messages are kept in a top-level collection rather than under their
+    /// channel, so that the scheduler can find them without listing every channel.
+    fn scheduled_messages(db: &Database) -> Collection<ScheduledMessage> {
+        db.collection(SCHEDULED_MESSAGES_COLLECTION)
+    }
+
+    /// Heartbeats are also kept in a top-level collection, keyed by channel ID, so that
+    /// overdue ones can be found without listing every channel.
+    fn heartbeats(db: &Database) -> Collection<Heartbeat> {
+        db.collection(HEARTBEATS_COLLECTION)
+    }
+}
+
+fn scheduled_document_id(channel_id: &str, message_id: &str) -> String {
+    format!("{}-{}", channel_id, message_id)
+}
+
+/// Idempotency keys are chosen by senders, so may contain characters (like `/`) that
+/// are not allowed in document IDs.
+fn idempotency_document_id(key: &str) -> String {
+    base64::encode_config(key, base64::URL_SAFE_NO_PAD)
+}
+
+/// Fetch the document with the given key, or `None` if there isn't one. Other errors,
+/// such as failing to reach Firestore, are returned.
+async fn get_optional<T>(collection: &Collection<T>, key: &str) -> Result<Option<T>>
+where
+    T: Serialize + DeserializeOwned + Unpin + Send + 'static,
+{
+    match collection.get(key).await {
+        Ok(value) => Ok(Some(value)),
+        Err(error)
+            if error
+                .downcast_ref::<tonic::Status>()
+                .is_some_and(|status| status.code() == tonic::Code::NotFound) =>
+        {
+            Ok(None)
+        }
+        Err(error) => Err(error),
+    }
+}
+
+/// Delete every document in a collection.
+async fn delete_all<T>(collection: &Collection<T>) -> Result<()>
+where
+    T: Serialize + DeserializeOwned + Unpin + Send + 'static,
+{
+    let document_ids: Vec<String> = collection
+        .list()
+        .with_page_size(LIST_PAGE_SIZE)
+        .map(|d| d.name.leaf_name().to_string())
+        .collect()
+        .await;
+
+    for document_id in document_ids {
+        collection.delete(&*document_id).await?;
+    }
+
+    Ok(())
+}
+
+impl Default for FirestoreDatabase {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl NotifyDatabase for FirestoreDatabase {
+    async fn create_channel(&self, channel: &Channel) -> Result<String> {
+        let db = self.db().await?;
+
+        let channel_id = Self::channels(&db)
+            .create(channel)
+            .await?
+            .leaf_name()
+            .to_string();
+
+        Ok(channel_id)
+    }
+
+    async fn get_channel(&self, channel_id: &str) -> Result<Channel> {
+        let db = self.db().await?;
+
+        Self::channels(&db).get(channel_id).await
+    }
+
+    async fn update_channel(&self, channel_id: &str, channel: &Channel) -> Result<()> {
+        let db = self.db().await?;
+
+        Self::channels(&db).update(channel, channel_id).await
+    }
+
+    async fn delete_channel(&self, channel_id: &str) -> Result<()> {
+        let db = self.db().await?;
+        let channels = Self::channels(&db);
+
+        // Deleting a document leaves its subcollections behind, so they are emptied first.
+        let subscriptions: Collection<Subscription> =
+            channels.subcollection(channel_id, SUBSCRIPTIONS_COLLECTION);
+        delete_all(&subscriptions).await?;
+        let messages: Collection<Message> = channels.subcollection(channel_id, MESSAGES_COLLECTION);
+        delete_all(&messages).await?;
+        delete_all(&Self::idempotency_keys(&db, channel_id)).await?;
+
+        let scheduled_messages = Self::scheduled_messages(&db);
+        let scheduled_ids: Vec<String> = scheduled_messages
+            .list()
+            .with_page_size(LIST_PAGE_SIZE)
+            .filter(|d| futures::future::ready(d.value.channel_id == channel_id))
+            .map(|d| d.name.leaf_name().to_string())
+            .collect()
+            .await;
+        for scheduled_id in scheduled_ids {
+            scheduled_messages.delete(&*scheduled_id).await?;
+        }
+
+        Self::heartbeats(&db).delete(channel_id).await?;
+
+        channels.delete(channel_id).await
+    }
+
+    async fn list_subscriptions(&self, channel_id: &str) -> Result<Vec<NamedRecord<Subscription>>> {
+        let db = self.db().await?;
+        let subscriptions: Collection<Subscription> =
+            Self::channels(&db).subcollection(channel_id, SUBSCRIPTIONS_COLLECTION);
+
+        let subscriptions = subscriptions
+            .list()
+            .with_page_size(LIST_PAGE_SIZE)
+            .filter(|d| futures::future::ready(d.value.deactivated.is_none()))
+            .map(|d| NamedRecord {
+                id: d.name.leaf_name().to_string(),
+                value: d.value,
+            })
+            .collect()
+            .await;
+
+        Ok(subscriptions)
+    }
+
+    async fn put_subscription(
+        &self,
+        channel_id: &str,
+        subscription_id: &str,
+        subscription: &Subscription,
+    ) -> Result<()> {
+        let db = self.db().await?;
+        let subscriptions: Collection<Subscription> =
+            Self::channels(&db).subcollection(channel_id, SUBSCRIPTIONS_COLLECTION);
+
+        subscriptions.upsert(subscription, subscription_id).await
+    }
+
+    async fn deactivate_subscription(
+        &self,
+        channel_id: &str,
+        subscription_id: &str,
+        deactivation: &Deactivation,
+    ) -> Result<()> {
+        let db = self.db().await?;
+        let subscriptions: Collection<Subscription> =
+            Self::channels(&db).subcollection(channel_id, SUBSCRIPTIONS_COLLECTION);
+
+        let mut subscription = subscriptions.get(subscription_id).await?;
+        subscription.deactivated = Some(deactivation.clone());
+
+        subscriptions.update(&subscription, subscription_id).await
+    }
+
+    async fn delete_subscription(&self, channel_id: &str, subscription_id: &str) -> Result<bool> {
+        let db = self.db().await?;
+        let subscriptions: Collection<Subscription> =
+            Self::channels(&db).subcollection(channel_id, SUBSCRIPTIONS_COLLECTION);
+
+        if subscriptions.get(subscription_id).await.is_err() {
+            return Ok(false);
+        }
+        subscriptions.delete(subscription_id).await?;
+
+        Ok(true)
+    }
+
+    async fn create_message(&self, channel_id: &str, message: &Message) -> Result<String> {
+        let db = self.db().await?;
+        let messages: Collection<Message> =
+            Self::channels(&db).subcollection(channel_id, MESSAGES_COLLECTION);
+
+        let message_id = messages.create(message).await?.leaf_name().to_string();
+
+        Ok(message_id)
+    }
+
+    async fn get_message(&self, channel_id: &str, message_id: &str) -> Result<Message> {
+        let db = self.db().await?;
+        let messages: Collection<Message> =
+            Self::channels(&db).subcollection(channel_id, MESSAGES_COLLECTION);
+
+        messages.get(message_id).await
+    }
+
+    async fn update_message(
+        &self,
+        channel_id: &str,
+        message_id: &str,
+        message: &Message,
+    ) -> Result<()> {
+        let db = self.db().await?;
+        let messages: Collection<Message> =
+            Self::channels(&db).subcollection(channel_id, MESSAGES_COLLECTION);
+
+        messages.update(message, message_id).await
+    }
+
+    async fn list_messages(
+        &self,
+        channel_id: &str,
+        offset: u32,
+        limit: u32,
+    ) -> Result<Vec<Message>> {
+        let db = self.db().await?;
+        let messages: Collection<Message> =
+            Self::channels(&db).subcollection(channel_id, MESSAGES_COLLECTION);
+
+        let messages = messages
+            .list()
+            .with_order_by("message_time desc")
+            .with_page_size(limit.min(LIST_PAGE_SIZE))
+            .skip(offset as usize)
+            .take(limit as usize)
+            .map(|d| d.value)
+            .collect()
+            .await;
+
+        Ok(messages)
+    }
+
+    async fn create_scheduled_message(&self, scheduled: &ScheduledMessage) -> Result<()> {
+        let db = self.db().await?;
+        let document_id = scheduled_document_id(&scheduled.channel_id, &scheduled.message_id);
+
+        Self::scheduled_messages(&db)
+            .create_with_key(scheduled, &*document_id)
+            .await
+    }
+
+    async fn list_due_scheduled_messages(
+        &self,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<ScheduledMessage>> {
+        let db = self.db().await?;
+
+        let due = Self::scheduled_messages(&db)
+            .list()
+            .with_order_by("deliver_at")
+            .with_page_size(LIST_PAGE_SIZE)
+            .map(|d| d.value)
+            .take_while(|scheduled| futures::future::ready(scheduled.deliver_at <= now))
+            .collect()
+            .await;
+
+        Ok(due)
+    }
+
+    async fn take_scheduled_message(&self, channel_id: &str, message_id: &str) -> Result<bool> {
+        let db = self.db().await?;
+        let scheduled_messages = Self::scheduled_messages(&db);
+        let document_id = scheduled_document_id(channel_id, message_id);
+
+        // Unlike the SQL backends, this is not atomic, so if two servers take the same
+        // message at once, it may be delivered twice.
+        if scheduled_messages.get(&*document_id).await.is_err() {
+            return Ok(false);
+        }
+        scheduled_messages.delete(&*document_id).await?;
+
+        Ok(true)
+    }
+
+    async fn get_heartbeat(&self, channel_id: &str) -> Result<Option<Heartbeat>> {
+        let db = self.db().await?;
+
+        get_optional(&Self::heartbeats(&db), channel_id).await
+    }
+
+    async fn put_heartbeat(&self, channel_id: &str, heartbeat: &Heartbeat) -> Result<()> {
+        let db = self.db().await?;
+
+        Self::heartbeats(&db).upsert(heartbeat, channel_id).await
+    }
+
+    async fn delete_heartbeat(&self, channel_id: &str) -> Result<()> {
+        let db = self.db().await?;
+
+        Self::heartbeats(&db).delete(channel_id).await
+    }
+
+    async fn list_overdue_heartbeats(
+        &self,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<NamedRecord<Heartbeat>>> {
+        let db = self.db().await?;
+
+        let overdue = Self::heartbeats(&db)
+            .list()
+            .with_order_by("deadline")
+            .with_page_size(LIST_PAGE_SIZE)
+            .take_while(|d| futures::future::ready(d.value.deadline <= now))
+            .filter(|d| futures::future::ready(!d.value.missed))
+            .map(|d| NamedRecord {
+                id: d.name.leaf_name().to_string(),
+                value: d.value,
+            })
+            .collect()
+            .await;
+
+        Ok(overdue)
+    }
+
+    async fn mark_heartbeat_missed(
+        &self,
+        channel_id: &str,
+        deadline: DateTime<Utc>,
+    ) -> Result<bool> {
+        let db = self.db().await?;
+        let heartbeats = Self::heartbeats(&db);
+
+        // Not atomic, so a ping arriving at the same time may be overwritten, in which case
+        // subscribers are told the heartbeat resumed on the next ping.
+        let mut heartbeat = heartbeats.get(channel_id).await?;
+        if heartbeat.deadline != deadline || heartbeat.missed {
+            return Ok(false);
+        }
+
+        heartbeat.missed = true;
+        heartbeats.update(&heartbeat, channel_id).await?;
+
+        Ok(true)
+    }
+
+    async fn get_idempotency_record(
+        &self,
+        channel_id: &str,
+        key: &str,
+    ) -> Result<Option<IdempotencyRecord>> {
+        let db = self.db().await?;
+
+        get_optional(
+            &Self::idempotency_keys(&db, channel_id),
+            &idempotency_document_id(key),
+        )
+        .await
+    }
+
+    async fn put_idempotency_record(
+        &self,
+        channel_id: &str,
+        key: &str,
+        record: &IdempotencyRecord,
+    ) -> Result<()> {
+        let db = self.db().await?;
+
+        Self::idempotency_keys(&db, channel_id)
+            .upsert(record, &*idempotency_document_id(key))
+            .await
+    }
+
+    async fn try_put_idempotency_record(
+        &self,
+        channel_id: &str,
+        key: &str,
+        record: &IdempotencyRecord,
+        replace_before: DateTime<Utc>,
+    ) -> Result<bool> {
+        let db = self.db().await?;
+        let idempotency_keys = Self::idempotency_keys(&db, channel_id);
+        let document_id = idempotency_document_id(key);
+
+        match get_optional(&idempotency_keys, &document_id).await? {
+            None => idempotency_keys.try_create(record, &*document_id).await,
+            Some(existing) if existing.time < replace_before => {
+                // Unlike creating a record, replacing an old one is not atomic, so two sends
+                // replacing it at once may both be sent.
+                idempotency_keys.upsert(record, &*document_id).await?;
+                Ok(true)
+            }
+            Some(_) => Ok(false),
+        }
+    }
+}
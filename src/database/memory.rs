@@ -0,0 +1,367 @@
+use super::{generate_id, NamedRecord, NotifyDatabase};
+use crate::model::{
+    Channel, Deactivation, Heartbeat, IdempotencyRecord, Message, ScheduledMessage, Subscription,
+};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+struct ChannelEntry {
+    channel: Channel,
+
+    /// Subscriptions, as (subscription ID, subscription) pairs in insertion order.
+    subscriptions: Vec<(String, Subscription)>,
+
+    /// Messages, as (message ID, message) pairs in insertion order.
+    messages: Vec<(String, Message)>,
+
+    idempotency_keys: HashMap<String, IdempotencyRecord>,
+}
+
+/// Storage held entirely in process memory. Data does not survive a restart.
+#[derive(Default)]
+pub struct MemoryDatabase {
+    channels: DashMap<String, ChannelEntry>,
+
+    /// Scheduled messages, keyed by (channel ID, message ID).
+    scheduled_messages: DashMap<(String, String), ScheduledMessage>,
+
+    /// Heartbeats, keyed by channel ID.
+    heartbeats: DashMap<String, Heartbeat>,
+}
+
+impl MemoryDatabase {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl NotifyDatabase for MemoryDatabase {
+    async fn create_channel(&self, channel: &Channel) -> Result<String> {
+        let channel_id = generate_id();
+
+        self.channels.insert(
+            channel_id.clone(),
+            ChannelEntry {
+                channel: channel.clone(),
+                subscriptions: Vec::new(),
+                messages: Vec::new(),
+                idempotency_keys: HashMap::new(),
+            },
+        );
+
+        Ok(channel_id)
+    }
+
+    async fn get_channel(&self, channel_id: &str) -> Result<Channel> {
+        self.channels
+            .get(channel_id)
+            .map(|entry| entry.channel.clone())
+            .ok_or_else(|| anyhow!("Channel not found."))
+    }
+
+    async fn update_channel(&self, channel_id: &str, channel: &Channel) -> Result<()> {
+        let mut entry = self
+            .channels
+            .get_mut(channel_id)
+            .ok_or_else(|| anyhow!("Channel not found."))?;
+
+        entry.channel = channel.clone();
+
+        Ok(())
+    }
+
+    async fn delete_channel(&self, channel_id: &str) -> Result<()> {
+        self.channels.remove(channel_id);
+        self.scheduled_messages
+            .retain(|(scheduled_channel_id, _), _| scheduled_channel_id != channel_id);
+        self.heartbeats.remove(channel_id);
+
+        Ok(())
+    }
+
+    async fn list_subscriptions(&self, channel_id: &str) -> Result<Vec<NamedRecord<Subscription>>> {
+        let entry = self
+            .channels
+            .get(channel_id)
+            .ok_or_else(|| anyhow!("Channel not found."))?;
+
+        Ok(entry
+            .subscriptions
+            .iter()
+            .filter(|(_, subscription)| subscription.deactivated.is_none())
+            .map(|(id, subscription)| NamedRecord {
+                id: id.clone(),
+                value: subscription.clone(),
+            })
+            .collect())
+    }
+
+    async fn put_subscription(
+        &self,
+        channel_id: &str,
+        subscription_id: &str,
+        subscription: &Subscription,
+    ) -> Result<()> {
+        let mut entry = self
+            .channels
+            .get_mut(channel_id)
+            .ok_or_else(|| anyhow!("Channel not found."))?;
+
+        match entry
+            .subscriptions
+            .iter_mut()
+            .find(|(id, _)| id == subscription_id)
+        {
+            Some((_, existing)) => *existing = subscription.clone(),
+            None => entry
+                .subscriptions
+                .push((subscription_id.to_string(), subscription.clone())),
+        }
+
+        Ok(())
+    }
+
+    async fn deactivate_subscription(
+        &self,
+        channel_id: &str,
+        subscription_id: &str,
+        deactivation: &Deactivation,
+    ) -> Result<()> {
+        let mut entry = self
+            .channels
+            .get_mut(channel_id)
+            .ok_or_else(|| anyhow!("Channel not found."))?;
+
+        let (_, subscription) = entry
+            .subscriptions
+            .iter_mut()
+            .find(|(id, _)| id == subscription_id)
+            .ok_or_else(|| anyhow!("Subscription not found."))?;
+        subscription.deactivated = Some(deactivation.clone());
+
+        Ok(())
+    }
+
+    async fn delete_subscription(&self, channel_id: &str, subscription_id: &str) -> Result<bool> {
+        let mut entry = self
+            .channels
+            .get_mut(channel_id)
+            .ok_or_else(|| anyhow!("Channel not found."))?;
+
+        let count = entry.subscriptions.len();
+        entry.subscriptions.retain(|(id, _)| id != subscription_id);
+
+        Ok(entry.subscriptions.len() < count)
+    }
+
+    async fn create_message(&self, channel_id: &str, message: &Message) -> Result<String> {
+        let mut entry = self
+            .channels
+            .get_mut(channel_id)
+            .ok_or_else(|| anyhow!("Channel not found."))?;
+
+        let message_id = generate_id();
+        entry.messages.push((message_id.clone(), message.clone()));
+
+        Ok(message_id)
+    }
+
+    async fn get_message(&self, channel_id: &str, message_id: &str) -> Result<Message> {
+        let entry = self
+            .channels
+            .get(channel_id)
+            .ok_or_else(|| anyhow!("Channel not found."))?;
+
+        entry
+            .messages
+            .iter()
+            .find(|(id, _)| id == message_id)
+            .map(|(_, message)| message.clone())
+            .ok_or_else(|| anyhow!("Message not found."))
+    }
+
+    async fn update_message(
+        &self,
+        channel_id: &str,
+        message_id: &str,
+        message: &Message,
+    ) -> Result<()> {
+        let mut entry = self
+            .channels
+            .get_mut(channel_id)
+            .ok_or_else(|| anyhow!("Channel not found."))?;
+
+        let (_, stored) = entry
+            .messages
+            .iter_mut()
+            .find(|(id, _)| id == message_id)
+            .ok_or_else(|| anyhow!("Message not found."))?;
+        *stored = message.clone();
+
+        Ok(())
+    }
+
+    async fn list_messages(
+        &self,
+        channel_id: &str,
+        offset: u32,
+        limit: u32,
+    ) -> Result<Vec<Message>> {
+        let entry = self
+            .channels
+            .get(channel_id)
+            .ok_or_else(|| anyhow!("Channel not found."))?;
+
+        let mut messages: Vec<Message> = entry
+            .messages
+            .iter()
+            .map(|(_, message)| message.clone())
+            .collect();
+        messages.sort_by_key(|message| Reverse(message.message_time));
+
+        Ok(messages
+            .into_iter()
+            .skip(offset as usize)
+            .take(limit as usize)
+            .collect())
+    }
+
+    async fn create_scheduled_message(&self, scheduled: &ScheduledMessage) -> Result<()> {
+        self.scheduled_messages.insert(
+            (scheduled.channel_id.clone(), scheduled.message_id.clone()),
+            scheduled.clone(),
+        );
+
+        Ok(())
+    }
+
+    async fn list_due_scheduled_messages(
+        &self,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<ScheduledMessage>> {
+        let mut due: Vec<ScheduledMessage> = self
+            .scheduled_messages
+            .iter()
+            .filter(|scheduled| scheduled.deliver_at <= now)
+            .map(|scheduled| scheduled.clone())
+            .collect();
+        due.sort_by_key(|scheduled| scheduled.deliver_at);
+
+        Ok(due)
+    }
+
+    async fn take_scheduled_message(&self, channel_id: &str, message_id: &str) -> Result<bool> {
+        Ok(self
+            .scheduled_messages
+            .remove(&(channel_id.to_string(), message_id.to_string()))
+            .is_some())
+    }
+
+    async fn get_heartbeat(&self, channel_id: &str) -> Result<Option<Heartbeat>> {
+        Ok(self
+            .heartbeats
+            .get(channel_id)
+            .map(|heartbeat| heartbeat.clone()))
+    }
+
+    async fn put_heartbeat(&self, channel_id: &str, heartbeat: &Heartbeat) -> Result<()> {
+        self.heartbeats
+            .insert(channel_id.to_string(), heartbeat.clone());
+
+        Ok(())
+    }
+
+    async fn delete_heartbeat(&self, channel_id: &str) -> Result<()> {
+        self.heartbeats.remove(channel_id);
+
+        Ok(())
+    }
+
+    async fn list_overdue_heartbeats(
+        &self,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<NamedRecord<Heartbeat>>> {
+        Ok(self
+            .heartbeats
+            .iter()
+            .filter(|heartbeat| heartbeat.deadline <= now && !heartbeat.missed)
+            .map(|heartbeat| NamedRecord {
+                id: heartbeat.key().clone(),
+                value: heartbeat.clone(),
+            })
+            .collect())
+    }
+
+    async fn mark_heartbeat_missed(
+        &self,
+        channel_id: &str,
+        deadline: DateTime<Utc>,
+    ) -> Result<bool> {
+        match self.heartbeats.get_mut(channel_id) {
+            Some(mut heartbeat) if heartbeat.deadline == deadline && !heartbeat.missed => {
+                heartbeat.missed = true;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn get_idempotency_record(
+        &self,
+        channel_id: &str,
+        key: &str,
+    ) -> Result<Option<IdempotencyRecord>> {
+        let entry = self
+            .channels
+            .get(channel_id)
+            .ok_or_else(|| anyhow!("Channel not found."))?;
+
+        Ok(entry.idempotency_keys.get(key).cloned())
+    }
+
+    async fn put_idempotency_record(
+        &self,
+        channel_id: &str,
+        key: &str,
+        record: &IdempotencyRecord,
+    ) -> Result<()> {
+        let mut entry = self
+            .channels
+            .get_mut(channel_id)
+            .ok_or_else(|| anyhow!("Channel not found."))?;
+
+        entry
+            .idempotency_keys
+            .insert(key.to_string(), record.clone());
+
+        Ok(())
+    }
+
+    async fn try_put_idempotency_record(
+        &self,
+        channel_id: &str,
+        key: &str,
+        record: &IdempotencyRecord,
+        replace_before: DateTime<Utc>,
+    ) -> Result<bool> {
+        let mut entry = self
+            .channels
+            .get_mut(channel_id)
+            .ok_or_else(|| anyhow!("Channel not found."))?;
+
+        match entry.idempotency_keys.get(key) {
+            Some(existing) if existing.time >= replace_before => Ok(false),
+            _ => {
+                entry
+                    .idempotency_keys
+                    .insert(key.to_string(), record.clone());
+                Ok(true)
+            }
+        }
+    }
+}
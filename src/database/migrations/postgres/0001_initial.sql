@@ -0,0 +1,27 @@
+CREATE TABLE channels (
+    id TEXT PRIMARY KEY,
+    created TIMESTAMPTZ NOT NULL,
+    created_agent TEXT NOT NULL,
+    created_ip TEXT NOT NULL
+);
+
+CREATE TABLE subscriptions (
+    channel_id TEXT NOT NULL REFERENCES channels (id) ON DELETE CASCADE,
+    id TEXT NOT NULL,
+    endpoint TEXT NOT NULL,
+    auth TEXT NOT NULL,
+    p256dh TEXT NOT NULL,
+    PRIMARY KEY (channel_id, id)
+);
+
+CREATE TABLE messages (
+    channel_id TEXT NOT NULL REFERENCES channels (id) ON DELETE CASCADE,
+    id TEXT NOT NULL,
+    message TEXT NOT NULL,
+    sender_ip TEXT NOT NULL,
+    message_time TIMESTAMPTZ NOT NULL,
+    result JSONB NOT NULL,
+    PRIMARY KEY (channel_id, id)
+);
+
+CREATE INDEX messages_channel_time ON messages (channel_id, message_time);
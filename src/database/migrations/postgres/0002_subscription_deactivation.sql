@@ -0,0 +1,2 @@
+ALTER TABLE subscriptions ADD COLUMN deactivated_time TIMESTAMPTZ;
+ALTER TABLE subscriptions ADD COLUMN deactivated_reason TEXT;
@@ -0,0 +1,2 @@
+ALTER TABLE messages ADD COLUMN title TEXT;
+ALTER TABLE messages ADD COLUMN body TEXT;
@@ -0,0 +1 @@
+ALTER TABLE messages ADD COLUMN actions JSONB NOT NULL DEFAULT '[]';
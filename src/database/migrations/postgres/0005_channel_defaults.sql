@@ -0,0 +1,2 @@
+ALTER TABLE channels ADD COLUMN default_vibrate BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE channels ADD COLUMN default_silent BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,7 @@
+CREATE TABLE idempotency_keys (
+    channel_id TEXT NOT NULL REFERENCES channels (id) ON DELETE CASCADE,
+    key TEXT NOT NULL,
+    message_id TEXT NOT NULL,
+    time TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (channel_id, key)
+);
@@ -0,0 +1,12 @@
+ALTER TABLE messages ADD COLUMN status TEXT NOT NULL DEFAULT 'sent';
+ALTER TABLE messages ADD COLUMN deliver_at TIMESTAMPTZ;
+
+CREATE TABLE scheduled_messages (
+    channel_id TEXT NOT NULL REFERENCES channels (id) ON DELETE CASCADE,
+    message_id TEXT NOT NULL,
+    deliver_at TIMESTAMPTZ NOT NULL,
+    payload TEXT NOT NULL,
+    PRIMARY KEY (channel_id, message_id)
+);
+
+CREATE INDEX scheduled_messages_deliver_at ON scheduled_messages (deliver_at);
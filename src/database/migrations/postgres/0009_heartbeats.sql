@@ -0,0 +1,10 @@
+CREATE TABLE heartbeats (
+    channel_id TEXT PRIMARY KEY REFERENCES channels (id) ON DELETE CASCADE,
+    interval_secs INTEGER NOT NULL,
+    grace_secs INTEGER NOT NULL,
+    last_ping TIMESTAMPTZ NOT NULL,
+    deadline TIMESTAMPTZ NOT NULL,
+    missed BOOLEAN NOT NULL
+);
+
+CREATE INDEX heartbeats_deadline ON heartbeats (deadline);
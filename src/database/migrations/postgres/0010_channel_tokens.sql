@@ -0,0 +1,2 @@
+ALTER TABLE channels ADD COLUMN send_token_hash TEXT;
+ALTER TABLE channels ADD COLUMN read_token_hash TEXT;
@@ -0,0 +1,4 @@
+ALTER TABLE channels ADD COLUMN name TEXT;
+ALTER TABLE channels ADD COLUMN description TEXT;
+ALTER TABLE channels ADD COLUMN default_action TEXT;
+ALTER TABLE channels ADD COLUMN default_icon TEXT;
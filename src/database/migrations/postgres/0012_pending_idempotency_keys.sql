@@ -0,0 +1 @@
+ALTER TABLE idempotency_keys ALTER COLUMN message_id DROP NOT NULL;
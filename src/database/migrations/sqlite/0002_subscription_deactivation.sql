@@ -0,0 +1,2 @@
+ALTER TABLE subscriptions ADD COLUMN deactivated_time TEXT;
+ALTER TABLE subscriptions ADD COLUMN deactivated_reason TEXT;
@@ -0,0 +1 @@
+ALTER TABLE messages ADD COLUMN actions TEXT NOT NULL DEFAULT '[]';
@@ -0,0 +1,2 @@
+ALTER TABLE channels ADD COLUMN default_vibrate INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE channels ADD COLUMN default_silent INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,10 @@
+CREATE TABLE heartbeats (
+    channel_id TEXT PRIMARY KEY REFERENCES channels (id),
+    interval_secs INTEGER NOT NULL,
+    grace_secs INTEGER NOT NULL,
+    last_ping TEXT NOT NULL,
+    deadline TEXT NOT NULL,
+    missed INTEGER NOT NULL
+);
+
+CREATE INDEX heartbeats_deadline ON heartbeats (deadline);
@@ -0,0 +1,14 @@
+CREATE TABLE idempotency_keys_new (
+    channel_id TEXT NOT NULL REFERENCES channels (id),
+    key TEXT NOT NULL,
+    message_id TEXT,
+    time TEXT NOT NULL,
+    PRIMARY KEY (channel_id, key)
+);
+
+INSERT INTO idempotency_keys_new (channel_id, key, message_id, time)
+SELECT channel_id, key, message_id, time FROM idempotency_keys;
+
+DROP TABLE idempotency_keys;
+
+ALTER TABLE idempotency_keys_new RENAME TO idempotency_keys;
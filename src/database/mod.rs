@@ -0,0 +1,161 @@
+use crate::model::{
+    Channel, Deactivation, Heartbeat, IdempotencyRecord, Message, ScheduledMessage, Subscription,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use rand::{distributions::Alphanumeric, Rng};
+
+pub mod firestore;
+pub mod memory;
+pub mod postgres;
+pub mod sqlite;
+
+/// Length of generated channel and message IDs, matching Firestore's auto-generated document IDs.
+const GENERATED_ID_LENGTH: usize = 20;
+
+/// Generate a random ID for backends that do not assign their own.
+pub fn generate_id() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(GENERATED_ID_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// A stored value along with the ID it is stored under.
+#[derive(Debug, Clone)]
+pub struct NamedRecord<T> {
+    pub id: String,
+    pub value: T,
+}
+
+/// Storage for channels and the subscriptions and messages that belong to them.
+///
+/// Handlers only talk to storage through this trait, so that the server can run
+/// against any backend that implements it.
+#[async_trait]
+pub trait NotifyDatabase: Send + Sync {
+    /// Store a new channel, returning its generated channel ID.
+    async fn create_channel(&self, channel: &Channel) -> Result<String>;
+
+    /// Fetch a channel. Returns an error if the channel does not exist.
+    async fn get_channel(&self, channel_id: &str) -> Result<Channel>;
+
+    /// Replace a stored channel, e.g. to change its settings.
+    async fn update_channel(&self, channel_id: &str, channel: &Channel) -> Result<()>;
+
+    /// Delete a channel along with everything stored for it: its subscriptions, messages,
+    /// idempotency keys, scheduled messages and heartbeat.
+    async fn delete_channel(&self, channel_id: &str) -> Result<()>;
+
+    /// List all active (not deactivated) subscriptions of a channel.
+    async fn list_subscriptions(&self, channel_id: &str) -> Result<Vec<NamedRecord<Subscription>>>;
+
+    /// Store a subscription under a client-supplied ID, replacing any existing subscription
+    /// with that ID (e.g. when a browser's push endpoint or keys change).
+    async fn put_subscription(
+        &self,
+        channel_id: &str,
+        subscription_id: &str,
+        subscription: &Subscription,
+    ) -> Result<()>;
+
+    /// Mark a subscription as deactivated, so that it is no longer listed.
+    async fn deactivate_subscription(
+        &self,
+        channel_id: &str,
+        subscription_id: &str,
+        deactivation: &Deactivation,
+    ) -> Result<()>;
+
+    /// Remove a subscription entirely, e.g. when its browser unsubscribes.
+    /// Returns `false` if there was no such subscription.
+    async fn delete_subscription(&self, channel_id: &str, subscription_id: &str) -> Result<bool>;
+
+    /// Store a new message, returning its generated message ID.
+    async fn create_message(&self, channel_id: &str, message: &Message) -> Result<String>;
+
+    /// Fetch a message. Returns an error if the message does not exist.
+    async fn get_message(&self, channel_id: &str, message_id: &str) -> Result<Message>;
+
+    /// Replace a stored message, e.g. to record the result of delivering it.
+    async fn update_message(
+        &self,
+        channel_id: &str,
+        message_id: &str,
+        message: &Message,
+    ) -> Result<()>;
+
+    /// List (up to `limit`) messages of a channel, most recent first,
+    /// skipping the `offset` most recent.
+    async fn list_messages(
+        &self,
+        channel_id: &str,
+        offset: u32,
+        limit: u32,
+    ) -> Result<Vec<Message>>;
+
+    /// Store a message to be delivered later. The message itself must already be stored.
+    async fn create_scheduled_message(&self, scheduled: &ScheduledMessage) -> Result<()>;
+
+    /// List scheduled messages (of any channel) due to be delivered at or before `now`.
+    async fn list_due_scheduled_messages(
+        &self,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<ScheduledMessage>>;
+
+    /// Remove a scheduled message, so that it is not delivered by anyone else.
+    /// Returns `true` if it was removed, or `false` if it was no longer scheduled.
+    async fn take_scheduled_message(&self, channel_id: &str, message_id: &str) -> Result<bool>;
+
+    /// Fetch a channel's heartbeat, if it has one.
+    async fn get_heartbeat(&self, channel_id: &str) -> Result<Option<Heartbeat>>;
+
+    /// Set a channel's heartbeat, replacing any existing one.
+    async fn put_heartbeat(&self, channel_id: &str, heartbeat: &Heartbeat) -> Result<()>;
+
+    /// Remove a channel's heartbeat, if it has one.
+    async fn delete_heartbeat(&self, channel_id: &str) -> Result<()>;
+
+    /// List heartbeats (as channel ID and heartbeat) whose deadline is at or before `now`,
+    /// and which have not already been marked as missed.
+    async fn list_overdue_heartbeats(
+        &self,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<NamedRecord<Heartbeat>>>;
+
+    /// Mark a channel's heartbeat as missed, unless it has been pinged since its deadline
+    /// was read (so that its deadline has changed) or it is already marked as missed.
+    /// Returns `true` if it was marked.
+    async fn mark_heartbeat_missed(
+        &self,
+        channel_id: &str,
+        deadline: DateTime<Utc>,
+    ) -> Result<bool>;
+
+    /// Fetch the record of the last message sent to a channel with the given idempotency key.
+    async fn get_idempotency_record(
+        &self,
+        channel_id: &str,
+        key: &str,
+    ) -> Result<Option<IdempotencyRecord>>;
+
+    /// Store the record of a message sent with an idempotency key, replacing any earlier one.
+    async fn put_idempotency_record(
+        &self,
+        channel_id: &str,
+        key: &str,
+        record: &IdempotencyRecord,
+    ) -> Result<()>;
+
+    /// Store the record of a send with an idempotency key, unless the key already has a
+    /// record from `replace_before` or later. Returns `true` if it was stored.
+    async fn try_put_idempotency_record(
+        &self,
+        channel_id: &str,
+        key: &str,
+        record: &IdempotencyRecord,
+        replace_before: DateTime<Utc>,
+    ) -> Result<bool>;
+}
@@ -0,0 +1,1175 @@
+use super::{generate_id, NamedRecord, NotifyDatabase};
+use crate::model::{
+    Channel, Deactivation, Heartbeat, IdempotencyRecord, Message, MessageStatus, ScheduledMessage,
+    Subscription,
+};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use deadpool_sqlite::{Config, Pool, Runtime};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+
+/// Schema migrations, applied in order. The number of migrations applied to a database
+/// is tracked in its `user_version` pragma, so existing entries must never be modified;
+/// schema changes are made by appending a new migration.
+const MIGRATIONS: &[&str] = &[
+    include_str!("migrations/sqlite/0001_initial.sql"),
+    include_str!("migrations/sqlite/0002_subscription_deactivation.sql"),
+    include_str!("migrations/sqlite/0003_message_title_body.sql"),
+    include_str!("migrations/sqlite/0004_message_actions.sql"),
+    include_str!("migrations/sqlite/0005_channel_defaults.sql"),
+    include_str!("migrations/sqlite/0006_message_priority.sql"),
+    include_str!("migrations/sqlite/0007_idempotency_keys.sql"),
+    include_str!("migrations/sqlite/0008_scheduled_messages.sql"),
+    include_str!("migrations/sqlite/0009_heartbeats.sql"),
+    include_str!("migrations/sqlite/0010_channel_tokens.sql"),
+    include_str!("migrations/sqlite/0011_channel_metadata.sql"),
+    include_str!("migrations/sqlite/0012_pending_idempotency_keys.sql"),
+];
+
+/// Apply any migrations that have not yet been applied to the given database.
+fn migrate(conn: &mut Connection) -> rusqlite::Result<()> {
+    let tx = conn.transaction()?;
+    let version: usize = tx.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        tracing::info!(%index, "Applying SQLite migration.");
+        tx.execute_batch(migration)?;
+    }
+
+    tx.pragma_update(None, "user_version", MIGRATIONS.len())?;
+    tx.commit()
+}
+
+/// Columns read by `MessageRow::from_row`.
+const MESSAGE_COLUMNS: &str =
+    "message, sender_ip, message_time, result, title, body, actions, priority, status, deliver_at";
+
+/// A message as read from the database, with the columns that hold JSON or enums still
+/// to be parsed.
+struct MessageRow {
+    message: Message,
+    result: String,
+    actions: String,
+    priority: Option<String>,
+    status: String,
+}
+
+impl MessageRow {
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        Ok(MessageRow {
+            message: Message {
+                message: row.get(0)?,
+                title: row.get(4)?,
+                body: row.get(5)?,
+                sender_ip: row.get(1)?,
+                message_time: row.get(2)?,
+                result: Vec::new(),
+                actions: Vec::new(),
+                priority: None,
+                status: MessageStatus::default(),
+                deliver_at: row.get(9)?,
+            },
+            result: row.get(3)?,
+            actions: row.get(6)?,
+            priority: row.get(7)?,
+            status: row.get(8)?,
+        })
+    }
+
+    fn into_message(self) -> Result<Message> {
+        Ok(Message {
+            result: serde_json::from_str(&self.result)?,
+            actions: serde_json::from_str(&self.actions)?,
+            priority: self.priority.as_deref().map(str::parse).transpose()?,
+            status: self.status.parse()?,
+            ..self.message
+        })
+    }
+}
+
+/// Columns read by `heartbeat_from_row`.
+const HEARTBEAT_COLUMNS: &str = "interval_secs, grace_secs, last_ping, deadline, missed";
+
+fn heartbeat_from_row(row: &rusqlite::Row) -> rusqlite::Result<Heartbeat> {
+    Ok(Heartbeat {
+        interval_secs: row.get(0)?,
+        grace_secs: row.get(1)?,
+        last_ping: row.get(2)?,
+        deadline: row.get(3)?,
+        missed: row.get(4)?,
+    })
+}
+
+/// Storage backed by a local SQLite database file.
+pub struct SqliteDatabase {
+    pool: Pool,
+}
+
+impl SqliteDatabase {
+    /// Open (creating if necessary) the SQLite database at the given path,
+    /// and bring its schema up to date.
+    pub async fn open(path: &Path) -> Result<Self> {
+        let pool = Config::new(path).create_pool(Runtime::Tokio1)?;
+        let db = SqliteDatabase { pool };
+
+        db.interact(migrate).await?;
+
+        Ok(db)
+    }
+
+    /// Run a blocking closure against a pooled connection.
+    async fn interact<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let conn = self.pool.get().await?;
+
+        let result = conn
+            .interact(f)
+            .await
+            .map_err(|e| anyhow!("SQLite interaction failed: {}", e))??;
+
+        Ok(result)
+    }
+}
+
+#[async_trait]
+impl NotifyDatabase for SqliteDatabase {
+    async fn create_channel(&self, channel: &Channel) -> Result<String> {
+        let channel_id = generate_id();
+        let id = channel_id.clone();
+        let created = channel.created;
+        let created_agent = channel.created_agent.clone();
+        let created_ip = channel.created_ip.clone();
+        let default_vibrate = channel.default_vibrate;
+        let default_silent = channel.default_silent;
+        let send_token_hash = channel.send_token_hash.clone();
+        let read_token_hash = channel.read_token_hash.clone();
+        let name = channel.name.clone();
+        let description = channel.description.clone();
+        let default_action = channel.default_action.clone();
+        let default_icon = channel.default_icon.clone();
+
+        self.interact(move |conn| {
+            conn.execute(
+                "INSERT INTO channels
+                (id, created, created_agent, created_ip, default_vibrate, default_silent,
+                send_token_hash, read_token_hash, name, description, default_action,
+                default_icon)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                params![
+                    id,
+                    created,
+                    created_agent,
+                    created_ip,
+                    default_vibrate,
+                    default_silent,
+                    send_token_hash,
+                    read_token_hash,
+                    name,
+                    description,
+                    default_action,
+                    default_icon
+                ],
+            )
+        })
+        .await?;
+
+        Ok(channel_id)
+    }
+
+    async fn get_channel(&self, channel_id: &str) -> Result<Channel> {
+        let channel_id = channel_id.to_string();
+
+        let channel = self
+            .interact(move |conn| {
+                conn.query_row(
+                    "SELECT created, created_agent, created_ip, default_vibrate, default_silent,
+                    send_token_hash, read_token_hash, name, description, default_action,
+                    default_icon
+                    FROM channels WHERE id = ?1",
+                    params![channel_id],
+                    |row| {
+                        Ok(Channel {
+                            created: row.get(0)?,
+                            created_agent: row.get(1)?,
+                            created_ip: row.get(2)?,
+                            default_vibrate: row.get(3)?,
+                            default_silent: row.get(4)?,
+                            send_token_hash: row.get(5)?,
+                            read_token_hash: row.get(6)?,
+                            name: row.get(7)?,
+                            description: row.get(8)?,
+                            default_action: row.get(9)?,
+                            default_icon: row.get(10)?,
+                        })
+                    },
+                )
+                .optional()
+            })
+            .await?;
+
+        channel.ok_or_else(|| anyhow!("Channel not found."))
+    }
+
+    async fn update_channel(&self, channel_id: &str, channel: &Channel) -> Result<()> {
+        let channel_id = channel_id.to_string();
+        let channel = channel.clone();
+
+        // The channel's creation details never change, so are not updated.
+        self.interact(move |conn| {
+            conn.execute(
+                "UPDATE channels SET default_vibrate = ?2, default_silent = ?3,
+                send_token_hash = ?4, read_token_hash = ?5, name = ?6, description = ?7,
+                default_action = ?8, default_icon = ?9
+                WHERE id = ?1",
+                params![
+                    channel_id,
+                    channel.default_vibrate,
+                    channel.default_silent,
+                    channel.send_token_hash,
+                    channel.read_token_hash,
+                    channel.name,
+                    channel.description,
+                    channel.default_action,
+                    channel.default_icon
+                ],
+            )
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn delete_channel(&self, channel_id: &str) -> Result<()> {
+        let channel_id = channel_id.to_string();
+
+        // Foreign keys are not enforced (and do not cascade), so each table is cleared.
+        self.interact(move |conn| {
+            let tx = conn.transaction()?;
+            for table in &[
+                "idempotency_keys",
+                "scheduled_messages",
+                "heartbeats",
+                "messages",
+                "subscriptions",
+            ] {
+                tx.execute(
+                    &format!("DELETE FROM {} WHERE channel_id = ?1", table),
+                    params![channel_id],
+                )?;
+            }
+            tx.execute("DELETE FROM channels WHERE id = ?1", params![channel_id])?;
+            tx.commit()
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn list_subscriptions(&self, channel_id: &str) -> Result<Vec<NamedRecord<Subscription>>> {
+        let channel_id = channel_id.to_string();
+
+        self.interact(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, endpoint, auth, p256dh FROM subscriptions
+                WHERE channel_id = ?1 AND deactivated_time IS NULL",
+            )?;
+
+            let rows = stmt.query_map(params![channel_id], |row| {
+                Ok(NamedRecord {
+                    id: row.get(0)?,
+                    value: Subscription {
+                        endpoint: row.get(1)?,
+                        auth: row.get(2)?,
+                        p256dh: row.get(3)?,
+                        deactivated: None,
+                    },
+                })
+            })?;
+
+            rows.collect()
+        })
+        .await
+    }
+
+    async fn put_subscription(
+        &self,
+        channel_id: &str,
+        subscription_id: &str,
+        subscription: &Subscription,
+    ) -> Result<()> {
+        let channel_id = channel_id.to_string();
+        let subscription_id = subscription_id.to_string();
+        let endpoint = subscription.endpoint.clone();
+        let auth = subscription.auth.clone();
+        let p256dh = subscription.p256dh.clone();
+
+        self.interact(move |conn| {
+            conn.execute(
+                "INSERT INTO subscriptions (channel_id, id, endpoint, auth, p256dh)
+                VALUES (?1, ?2, ?3, ?4, ?5)
+                ON CONFLICT (channel_id, id) DO UPDATE SET
+                endpoint = excluded.endpoint, auth = excluded.auth, p256dh = excluded.p256dh,
+                deactivated_time = NULL, deactivated_reason = NULL",
+                params![channel_id, subscription_id, endpoint, auth, p256dh],
+            )
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn deactivate_subscription(
+        &self,
+        channel_id: &str,
+        subscription_id: &str,
+        deactivation: &Deactivation,
+    ) -> Result<()> {
+        let channel_id = channel_id.to_string();
+        let subscription_id = subscription_id.to_string();
+        let time = deactivation.time;
+        let reason = deactivation.reason.clone();
+
+        self.interact(move |conn| {
+            conn.execute(
+                "UPDATE subscriptions SET deactivated_time = ?3, deactivated_reason = ?4
+                WHERE channel_id = ?1 AND id = ?2",
+                params![channel_id, subscription_id, time, reason],
+            )
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn delete_subscription(&self, channel_id: &str, subscription_id: &str) -> Result<bool> {
+        let channel_id = channel_id.to_string();
+        let subscription_id = subscription_id.to_string();
+
+        let deleted = self
+            .interact(move |conn| {
+                conn.execute(
+                    "DELETE FROM subscriptions WHERE channel_id = ?1 AND id = ?2",
+                    params![channel_id, subscription_id],
+                )
+            })
+            .await?;
+
+        Ok(deleted == 1)
+    }
+
+    async fn create_message(&self, channel_id: &str, message: &Message) -> Result<String> {
+        let message_id = generate_id();
+        let id = message_id.clone();
+        let channel_id = channel_id.to_string();
+        let text = message.message.clone();
+        let sender_ip = message.sender_ip.clone();
+        let message_time = message.message_time;
+        let result = serde_json::to_string(&message.result)?;
+        let title = message.title.clone();
+        let body = message.body.clone();
+        let actions = serde_json::to_string(&message.actions)?;
+        let priority = message.priority.map(|priority| priority.as_str());
+        let status = message.status.as_str();
+        let deliver_at = message.deliver_at;
+
+        self.interact(move |conn| {
+            conn.execute(
+                "INSERT INTO messages (channel_id, id, message, sender_ip, message_time, result,
+                title, body, actions, priority, status, deliver_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                params![
+                    channel_id,
+                    id,
+                    text,
+                    sender_ip,
+                    message_time,
+                    result,
+                    title,
+                    body,
+                    actions,
+                    priority,
+                    status,
+                    deliver_at
+                ],
+            )
+        })
+        .await?;
+
+        Ok(message_id)
+    }
+
+    async fn get_message(&self, channel_id: &str, message_id: &str) -> Result<Message> {
+        let channel_id = channel_id.to_string();
+        let message_id = message_id.to_string();
+
+        let row = self
+            .interact(move |conn| {
+                conn.query_row(
+                    &format!(
+                        "SELECT {} FROM messages WHERE channel_id = ?1 AND id = ?2",
+                        MESSAGE_COLUMNS
+                    ),
+                    params![channel_id, message_id],
+                    MessageRow::from_row,
+                )
+                .optional()
+            })
+            .await?;
+
+        row.ok_or_else(|| anyhow!("Message not found."))?
+            .into_message()
+    }
+
+    async fn update_message(
+        &self,
+        channel_id: &str,
+        message_id: &str,
+        message: &Message,
+    ) -> Result<()> {
+        let channel_id = channel_id.to_string();
+        let message_id = message_id.to_string();
+        let text = message.message.clone();
+        let sender_ip = message.sender_ip.clone();
+        let message_time = message.message_time;
+        let result = serde_json::to_string(&message.result)?;
+        let title = message.title.clone();
+        let body = message.body.clone();
+        let actions = serde_json::to_string(&message.actions)?;
+        let priority = message.priority.map(|priority| priority.as_str());
+        let status = message.status.as_str();
+        let deliver_at = message.deliver_at;
+
+        let updated = self
+            .interact(move |conn| {
+                conn.execute(
+                    "UPDATE messages SET message = ?3, sender_ip = ?4, message_time = ?5, result = ?6,
+                    title = ?7, body = ?8, actions = ?9, priority = ?10, status = ?11,
+                    deliver_at = ?12
+                    WHERE channel_id = ?1 AND id = ?2",
+                    params![
+                        channel_id,
+                        message_id,
+                        text,
+                        sender_ip,
+                        message_time,
+                        result,
+                        title,
+                        body,
+                        actions,
+                        priority,
+                        status,
+                        deliver_at
+                    ],
+                )
+            })
+            .await?;
+
+        if updated == 0 {
+            return Err(anyhow!("Message not found."));
+        }
+
+        Ok(())
+    }
+
+    async fn list_messages(
+        &self,
+        channel_id: &str,
+        offset: u32,
+        limit: u32,
+    ) -> Result<Vec<Message>> {
+        let channel_id = channel_id.to_string();
+
+        let rows = self
+            .interact(move |conn| {
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT {} FROM messages
+                    WHERE channel_id = ?1 ORDER BY message_time DESC LIMIT ?2 OFFSET ?3",
+                    MESSAGE_COLUMNS
+                ))?;
+
+                let rows =
+                    stmt.query_map(params![channel_id, limit, offset], MessageRow::from_row)?;
+
+                rows.collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .await?;
+
+        rows.into_iter().map(MessageRow::into_message).collect()
+    }
+
+    async fn create_scheduled_message(&self, scheduled: &ScheduledMessage) -> Result<()> {
+        let channel_id = scheduled.channel_id.clone();
+        let message_id = scheduled.message_id.clone();
+        let deliver_at = scheduled.deliver_at;
+        let payload = scheduled.payload.clone();
+
+        self.interact(move |conn| {
+            conn.execute(
+                "INSERT INTO scheduled_messages (channel_id, message_id, deliver_at, payload)
+                VALUES (?1, ?2, ?3, ?4)",
+                params![channel_id, message_id, deliver_at, payload],
+            )
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn list_due_scheduled_messages(
+        &self,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<ScheduledMessage>> {
+        self.interact(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT channel_id, message_id, deliver_at, payload FROM scheduled_messages
+                WHERE deliver_at <= ?1 ORDER BY deliver_at",
+            )?;
+
+            let rows = stmt.query_map(params![now], |row| {
+                Ok(ScheduledMessage {
+                    channel_id: row.get(0)?,
+                    message_id: row.get(1)?,
+                    deliver_at: row.get(2)?,
+                    payload: row.get(3)?,
+                })
+            })?;
+
+            rows.collect()
+        })
+        .await
+    }
+
+    async fn take_scheduled_message(&self, channel_id: &str, message_id: &str) -> Result<bool> {
+        let channel_id = channel_id.to_string();
+        let message_id = message_id.to_string();
+
+        let deleted = self
+            .interact(move |conn| {
+                conn.execute(
+                    "DELETE FROM scheduled_messages WHERE channel_id = ?1 AND message_id = ?2",
+                    params![channel_id, message_id],
+                )
+            })
+            .await?;
+
+        Ok(deleted == 1)
+    }
+
+    async fn get_heartbeat(&self, channel_id: &str) -> Result<Option<Heartbeat>> {
+        let channel_id = channel_id.to_string();
+
+        self.interact(move |conn| {
+            conn.query_row(
+                &format!(
+                    "SELECT {} FROM heartbeats WHERE channel_id = ?1",
+                    HEARTBEAT_COLUMNS
+                ),
+                params![channel_id],
+                heartbeat_from_row,
+            )
+            .optional()
+        })
+        .await
+    }
+
+    async fn put_heartbeat(&self, channel_id: &str, heartbeat: &Heartbeat) -> Result<()> {
+        let channel_id = channel_id.to_string();
+        let heartbeat = heartbeat.clone();
+
+        self.interact(move |conn| {
+            conn.execute(
+                "INSERT INTO heartbeats
+                (channel_id, interval_secs, grace_secs, last_ping, deadline, missed)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                ON CONFLICT (channel_id) DO UPDATE
+                SET interval_secs = excluded.interval_secs, grace_secs = excluded.grace_secs,
+                last_ping = excluded.last_ping, deadline = excluded.deadline,
+                missed = excluded.missed",
+                params![
+                    channel_id,
+                    heartbeat.interval_secs,
+                    heartbeat.grace_secs,
+                    heartbeat.last_ping,
+                    heartbeat.deadline,
+                    heartbeat.missed
+                ],
+            )
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn delete_heartbeat(&self, channel_id: &str) -> Result<()> {
+        let channel_id = channel_id.to_string();
+
+        self.interact(move |conn| {
+            conn.execute(
+                "DELETE FROM heartbeats WHERE channel_id = ?1",
+                params![channel_id],
+            )
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn list_overdue_heartbeats(
+        &self,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<NamedRecord<Heartbeat>>> {
+        self.interact(move |conn| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {}, channel_id FROM heartbeats
+                WHERE deadline <= ?1 AND NOT missed ORDER BY deadline",
+                HEARTBEAT_COLUMNS
+            ))?;
+
+            let rows = stmt.query_map(params![now], |row| {
+                Ok(NamedRecord {
+                    id: row.get(5)?,
+                    value: heartbeat_from_row(row)?,
+                })
+            })?;
+
+            rows.collect()
+        })
+        .await
+    }
+
+    async fn mark_heartbeat_missed(
+        &self,
+        channel_id: &str,
+        deadline: DateTime<Utc>,
+    ) -> Result<bool> {
+        let channel_id = channel_id.to_string();
+
+        let updated = self
+            .interact(move |conn| {
+                conn.execute(
+                    "UPDATE heartbeats SET missed = 1
+                    WHERE channel_id = ?1 AND deadline = ?2 AND NOT missed",
+                    params![channel_id, deadline],
+                )
+            })
+            .await?;
+
+        Ok(updated == 1)
+    }
+
+    async fn get_idempotency_record(
+        &self,
+        channel_id: &str,
+        key: &str,
+    ) -> Result<Option<IdempotencyRecord>> {
+        let channel_id = channel_id.to_string();
+        let key = key.to_string();
+
+        self.interact(move |conn| {
+            conn.query_row(
+                "SELECT message_id, time FROM idempotency_keys WHERE channel_id = ?1 AND key = ?2",
+                params![channel_id, key],
+                |row| {
+                    Ok(IdempotencyRecord {
+                        message_id: row.get(0)?,
+                        time: row.get(1)?,
+                    })
+                },
+            )
+            .optional()
+        })
+        .await
+    }
+
+    async fn put_idempotency_record(
+        &self,
+        channel_id: &str,
+        key: &str,
+        record: &IdempotencyRecord,
+    ) -> Result<()> {
+        let channel_id = channel_id.to_string();
+        let key = key.to_string();
+        let message_id = record.message_id.clone();
+        let time = record.time;
+
+        self.interact(move |conn| {
+            conn.execute(
+                "INSERT INTO idempotency_keys (channel_id, key, message_id, time)
+                VALUES (?1, ?2, ?3, ?4)
+                ON CONFLICT (channel_id, key) DO UPDATE
+                SET message_id = excluded.message_id, time = excluded.time",
+                params![channel_id, key, message_id, time],
+            )
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn try_put_idempotency_record(
+        &self,
+        channel_id: &str,
+        key: &str,
+        record: &IdempotencyRecord,
+        replace_before: DateTime<Utc>,
+    ) -> Result<bool> {
+        let channel_id = channel_id.to_string();
+        let key = key.to_string();
+        let message_id = record.message_id.clone();
+        let time = record.time;
+
+        let stored = self
+            .interact(move |conn| {
+                conn.execute(
+                    "INSERT INTO idempotency_keys (channel_id, key, message_id, time)
+                    VALUES (?1, ?2, ?3, ?4)
+                    ON CONFLICT (channel_id, key) DO UPDATE
+                    SET message_id = excluded.message_id, time = excluded.time
+                    WHERE idempotency_keys.time < ?5",
+                    params![channel_id, key, message_id, time, replace_before],
+                )
+            })
+            .await?;
+
+        Ok(stored > 0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::{MessageAction, MessageResult, Priority};
+    use chrono::{DateTime, Duration, Utc};
+    use std::path::PathBuf;
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("notify-run-test-{}.sqlite", generate_id()))
+    }
+
+    fn channel() -> Channel {
+        Channel {
+            created: "2021-10-01T12:00:00Z".parse().unwrap(),
+            created_agent: "test-agent".to_string(),
+            created_ip: "127.0.0.1".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_migrations_are_idempotent() {
+        let path = temp_path();
+
+        SqliteDatabase::open(&path).await.unwrap();
+        let db = SqliteDatabase::open(&path).await.unwrap();
+
+        let channel_id = db.create_channel(&channel()).await.unwrap();
+        db.get_channel(&channel_id).await.unwrap();
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_channels_and_subscriptions() {
+        let path = temp_path();
+        let db = SqliteDatabase::open(&path).await.unwrap();
+
+        let channel_id = db.create_channel(&channel()).await.unwrap();
+        assert_eq!(
+            "test-agent",
+            db.get_channel(&channel_id).await.unwrap().created_agent
+        );
+        assert!(db.get_channel("missing").await.is_err());
+
+        let mut channel = db.get_channel(&channel_id).await.unwrap();
+        channel.name = Some("Backups".to_string());
+        channel.default_icon = Some("https://example.com/icon.png".to_string());
+        db.update_channel(&channel_id, &channel).await.unwrap();
+        let channel = db.get_channel(&channel_id).await.unwrap();
+        assert_eq!(Some("Backups"), channel.name.as_deref());
+        assert_eq!(
+            Some("https://example.com/icon.png"),
+            channel.default_icon.as_deref()
+        );
+        assert!(channel.description.is_none());
+
+        let subscription = Subscription {
+            endpoint: "https://push.example.com/abc".to_string(),
+            auth: "auth".to_string(),
+            p256dh: "p256dh".to_string(),
+            deactivated: None,
+        };
+
+        db.put_subscription(&channel_id, "sub1", &subscription)
+            .await
+            .unwrap();
+
+        // Storing under the same ID replaces the endpoint and keys.
+        let subscription = Subscription {
+            endpoint: "https://push.example.com/def".to_string(),
+            ..subscription
+        };
+        db.put_subscription(&channel_id, "sub1", &subscription)
+            .await
+            .unwrap();
+
+        let subscriptions = db.list_subscriptions(&channel_id).await.unwrap();
+        assert_eq!(1, subscriptions.len());
+        assert_eq!("sub1", subscriptions[0].id);
+        assert_eq!(subscription.endpoint, subscriptions[0].value.endpoint);
+
+        db.deactivate_subscription(
+            &channel_id,
+            "sub1",
+            &Deactivation {
+                time: Utc::now(),
+                reason: "Gone".to_string(),
+            },
+        )
+        .await
+        .unwrap();
+        assert!(db.list_subscriptions(&channel_id).await.unwrap().is_empty());
+
+        // Storing it again reactivates it.
+        db.put_subscription(&channel_id, "sub1", &subscription)
+            .await
+            .unwrap();
+        assert_eq!(1, db.list_subscriptions(&channel_id).await.unwrap().len());
+
+        assert!(db.delete_subscription(&channel_id, "sub1").await.unwrap());
+        assert!(!db.delete_subscription(&channel_id, "sub1").await.unwrap());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_messages_listed_most_recent_first() {
+        let path = temp_path();
+        let db = SqliteDatabase::open(&path).await.unwrap();
+
+        let channel_id = db.create_channel(&channel()).await.unwrap();
+        let start: DateTime<Utc> = "2021-10-01T12:00:00Z".parse().unwrap();
+
+        for i in 0..3 {
+            db.create_message(
+                &channel_id,
+                &Message {
+                    message: format!("message {}", i),
+                    sender_ip: "127.0.0.1".to_string(),
+                    message_time: start + Duration::minutes(i),
+                    result: vec![MessageResult {
+                        endpoint_domain: "push.example.com".to_string(),
+                        result_status: "201".to_string(),
+                        attempts: 1,
+                    }],
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        }
+
+        let messages = db.list_messages(&channel_id, 0, 2).await.unwrap();
+        assert_eq!(
+            vec!["message 2", "message 1"],
+            messages.iter().map(|m| &*m.message).collect::<Vec<_>>()
+        );
+        assert_eq!("201", messages[0].result[0].result_status);
+
+        let messages = db.list_messages(&channel_id, 2, 2).await.unwrap();
+        assert_eq!(
+            vec!["message 0"],
+            messages.iter().map(|m| &*m.message).collect::<Vec<_>>()
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_message_fields_round_trip() {
+        let path = temp_path();
+        let db = SqliteDatabase::open(&path).await.unwrap();
+
+        let channel_id = db.create_channel(&channel()).await.unwrap();
+        let mut message = Message {
+            message: "Deployed\nv1.2 is live".to_string(),
+            title: Some("Deployed".to_string()),
+            body: Some("v1.2 is live".to_string()),
+            priority: Some(Priority::High),
+            actions: vec![MessageAction {
+                label: "Open logs".to_string(),
+                url: "https://logs.example.com/".to_string(),
+            }],
+            sender_ip: "127.0.0.1".to_string(),
+            message_time: "2021-10-01T12:00:00Z".parse().unwrap(),
+            ..Default::default()
+        };
+        let message_id = db.create_message(&channel_id, &message).await.unwrap();
+
+        message.result.push(MessageResult {
+            endpoint_domain: "push.example.com".to_string(),
+            result_status: "201".to_string(),
+            attempts: 2,
+        });
+        db.update_message(&channel_id, &message_id, &message)
+            .await
+            .unwrap();
+
+        let stored = db.list_messages(&channel_id, 0, 1).await.unwrap().remove(0);
+        assert_eq!(Some("Deployed"), stored.title.as_deref());
+        assert_eq!(Some("v1.2 is live"), stored.body.as_deref());
+        assert_eq!(message.actions, stored.actions);
+        assert_eq!(Some(Priority::High), stored.priority);
+        assert_eq!(2, stored.result[0].attempts);
+
+        let stored = db.get_message(&channel_id, &message_id).await.unwrap();
+        assert_eq!(message.message, stored.message);
+        assert!(db.get_message(&channel_id, "nosuchmessage").await.is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_messages() {
+        let path = temp_path();
+        let db = SqliteDatabase::open(&path).await.unwrap();
+
+        let channel_id = db.create_channel(&channel()).await.unwrap();
+        let now: DateTime<Utc> = "2021-10-01T12:00:00Z".parse().unwrap();
+        for (message_id, minutes) in [("later", 5), ("due", -5), ("now", 0)] {
+            db.create_scheduled_message(&ScheduledMessage {
+                channel_id: channel_id.clone(),
+                message_id: message_id.to_string(),
+                deliver_at: now + Duration::minutes(minutes),
+                payload: "{}".to_string(),
+            })
+            .await
+            .unwrap();
+        }
+
+        let due = db.list_due_scheduled_messages(now).await.unwrap();
+        assert_eq!(
+            vec!["due", "now"],
+            due.iter()
+                .map(|scheduled| scheduled.message_id.as_str())
+                .collect::<Vec<_>>()
+        );
+
+        assert!(db.take_scheduled_message(&channel_id, "due").await.unwrap());
+        assert!(!db.take_scheduled_message(&channel_id, "due").await.unwrap());
+        assert_eq!(1, db.list_due_scheduled_messages(now).await.unwrap().len());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_heartbeats() {
+        let path = temp_path();
+        let db = SqliteDatabase::open(&path).await.unwrap();
+
+        let channel_id = db.create_channel(&channel()).await.unwrap();
+        assert!(db.get_heartbeat(&channel_id).await.unwrap().is_none());
+
+        let now: DateTime<Utc> = "2021-10-01T12:00:00Z".parse().unwrap();
+        let mut heartbeat = Heartbeat {
+            interval_secs: 3600,
+            grace_secs: 60,
+            last_ping: now - Duration::seconds(3660),
+            deadline: now,
+            missed: false,
+        };
+        db.put_heartbeat(&channel_id, &heartbeat).await.unwrap();
+        assert_eq!(
+            Some(heartbeat.clone()),
+            db.get_heartbeat(&channel_id).await.unwrap()
+        );
+
+        let overdue = db.list_overdue_heartbeats(now).await.unwrap();
+        assert_eq!(channel_id, overdue[0].id);
+        assert!(db
+            .list_overdue_heartbeats(now - Duration::seconds(1))
+            .await
+            .unwrap()
+            .is_empty());
+
+        // A heartbeat is only marked missed if it hasn't been pinged since it was listed.
+        heartbeat.deadline = now + Duration::hours(1);
+        db.put_heartbeat(&channel_id, &heartbeat).await.unwrap();
+        assert!(!db.mark_heartbeat_missed(&channel_id, now).await.unwrap());
+        assert!(db
+            .mark_heartbeat_missed(&channel_id, heartbeat.deadline)
+            .await
+            .unwrap());
+        assert!(db
+            .list_overdue_heartbeats(heartbeat.deadline)
+            .await
+            .unwrap()
+            .is_empty());
+
+        db.delete_heartbeat(&channel_id).await.unwrap();
+        assert!(db.get_heartbeat(&channel_id).await.unwrap().is_none());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_records() {
+        let path = temp_path();
+        let db = SqliteDatabase::open(&path).await.unwrap();
+
+        let channel_id = db.create_channel(&channel()).await.unwrap();
+        assert!(db
+            .get_idempotency_record(&channel_id, "key")
+            .await
+            .unwrap()
+            .is_none());
+
+        for message_id in ["first", "second"] {
+            let record = IdempotencyRecord {
+                message_id: Some(message_id.to_string()),
+                time: "2021-10-01T12:00:00Z".parse().unwrap(),
+            };
+            db.put_idempotency_record(&channel_id, "key", &record)
+                .await
+                .unwrap();
+        }
+
+        let record = db
+            .get_idempotency_record(&channel_id, "key")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(Some("second"), record.message_id.as_deref());
+
+        // A claim only replaces a record made before the given time.
+        let claim = IdempotencyRecord {
+            message_id: None,
+            time: "2021-10-01T13:00:00Z".parse().unwrap(),
+        };
+        for (replace_before, stored) in [
+            ("2021-10-01T12:00:00Z", false),
+            ("2021-10-01T12:00:01Z", true),
+        ] {
+            let replace_before = replace_before.parse().unwrap();
+            assert_eq!(
+                stored,
+                db.try_put_idempotency_record(&channel_id, "key", &claim, replace_before)
+                    .await
+                    .unwrap()
+            );
+        }
+        let record = db
+            .get_idempotency_record(&channel_id, "key")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(None, record.message_id);
+        assert!(db
+            .try_put_idempotency_record(&channel_id, "other", &claim, claim.time)
+            .await
+            .unwrap());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_delete_channel() {
+        let path = temp_path();
+        let db = SqliteDatabase::open(&path).await.unwrap();
+
+        let now: DateTime<Utc> = "2021-10-01T12:00:00Z".parse().unwrap();
+        let subscription = Subscription {
+            endpoint: "https://push.example.com/abc".to_string(),
+            auth: "auth".to_string(),
+            p256dh: "p256dh".to_string(),
+            deactivated: None,
+        };
+
+        let kept_channel_id = db.create_channel(&channel()).await.unwrap();
+        db.put_subscription(&kept_channel_id, "sub1", &subscription)
+            .await
+            .unwrap();
+
+        let channel_id = db.create_channel(&channel()).await.unwrap();
+        db.put_subscription(&channel_id, "sub1", &subscription)
+            .await
+            .unwrap();
+        let message_id = db
+            .create_message(
+                &channel_id,
+                &Message {
+                    message: "hello".to_string(),
+                    sender_ip: "127.0.0.1".to_string(),
+                    message_time: now,
+                    status: MessageStatus::Pending,
+                    deliver_at: Some(now),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        db.create_scheduled_message(&ScheduledMessage {
+            channel_id: channel_id.clone(),
+            message_id: message_id.clone(),
+            deliver_at: now,
+            payload: "{}".to_string(),
+        })
+        .await
+        .unwrap();
+        db.put_heartbeat(
+            &channel_id,
+            &Heartbeat {
+                interval_secs: 3600,
+                grace_secs: 60,
+                last_ping: now,
+                deadline: now,
+                missed: false,
+            },
+        )
+        .await
+        .unwrap();
+        db.put_idempotency_record(
+            &channel_id,
+            "key",
+            &IdempotencyRecord {
+                message_id: Some(message_id),
+                time: now,
+            },
+        )
+        .await
+        .unwrap();
+
+        db.delete_channel(&channel_id).await.unwrap();
+
+        assert!(db.get_channel(&channel_id).await.is_err());
+        assert!(db.list_subscriptions(&channel_id).await.unwrap().is_empty());
+        assert!(db
+            .list_messages(&channel_id, 0, 10)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(db
+            .list_due_scheduled_messages(now)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(db.get_heartbeat(&channel_id).await.unwrap().is_none());
+        assert!(db
+            .get_idempotency_record(&channel_id, "key")
+            .await
+            .unwrap()
+            .is_none());
+
+        assert!(db.get_channel(&kept_channel_id).await.is_ok());
+        assert_eq!(
+            1,
+            db.list_subscriptions(&kept_channel_id).await.unwrap().len()
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,984 @@
+use crate::database::{NamedRecord, NotifyDatabase};
+use crate::model::{
+    Deactivation, Message, MessageResult, MessageStatus, Priority, ScheduledMessage, Subscription,
+};
+use crate::server_state::ServerState;
+use crate::vapid::{send_message, MessagePayload, SendFailure};
+use anyhow::Result;
+use axum::http::Uri;
+use chrono::Utc;
+use futures::{stream, StreamExt};
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    Mutex,
+};
+use tokio::time::{sleep, timeout, Instant};
+
+/// Timeout (seconds) of external service when invoking push request.
+const TIMEOUT_SECS: u64 = 10;
+
+/// Maximum number of push requests in flight at once when sending a message.
+const MAX_CONCURRENT_PUSHES: usize = 16;
+
+/// Maximum number of messages waiting for a delivery worker before new ones are refused.
+const DELIVERY_QUEUE_CAPACITY: usize = 1000;
+
+/// How often (seconds) to check for scheduled messages that are due to be delivered.
+const SCHEDULER_INTERVAL_SECS: u64 = 10;
+
+/// How push requests that fail for transient reasons are retried.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Total number of attempts made for each subscription, including the first.
+    pub max_attempts: u32,
+
+    /// Delay before the first retry. Each subsequent retry waits twice as long as the last.
+    pub base_delay: Duration,
+
+    /// Upper bound on the delay between attempts. If a push service asks us to wait
+    /// longer than this (with a Retry-After header), we give up instead.
+    pub max_delay: Duration,
+
+    /// Total time that retries may take while the sender of a message waits for its
+    /// results. Retries that would not start within it are not made.
+    pub sync_budget: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            sync_budget: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Read the retry policy from the environment, using defaults for any variables not set.
+    pub fn from_env() -> Self {
+        let default = RetryPolicy::default();
+
+        let env_u64 = |key: &str| -> Option<u64> {
+            std::env::var(key).ok().map(|value| {
+                value
+                    .parse()
+                    .unwrap_or_else(|_| panic!("Expected {} to be an integer.", key))
+            })
+        };
+
+        RetryPolicy {
+            max_attempts: env_u64("NOTIFY_PUSH_MAX_ATTEMPTS")
+                .map(|v| v.max(1) as u32)
+                .unwrap_or(default.max_attempts),
+            base_delay: env_u64("NOTIFY_PUSH_RETRY_BASE_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(default.base_delay),
+            max_delay: env_u64("NOTIFY_PUSH_RETRY_MAX_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(default.max_delay),
+            sync_budget: env_u64("NOTIFY_PUSH_SYNC_BUDGET_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(default.sync_budget),
+        }
+    }
+
+    /// Delay before retrying after the given (1-based) attempt has failed, or `None`
+    /// if we should give up.
+    fn delay_after(&self, attempt: u32, retry_after: Option<Duration>) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+
+        match retry_after {
+            Some(retry_after) if retry_after > self.max_delay => None,
+            Some(retry_after) => Some(retry_after),
+            None => {
+                let factor = 2u32.saturating_pow(attempt - 1);
+                Some(self.base_delay.saturating_mul(factor).min(self.max_delay))
+            }
+        }
+    }
+}
+
+/// Send a message to every active subscription of a channel, returning the result for each.
+pub async fn deliver(
+    server_state: &ServerState,
+    channel_id: &str,
+    payload: &MessagePayload,
+) -> Result<Vec<MessageResult>> {
+    deliver_before(server_state, channel_id, payload, None).await
+}
+
+/// Like `deliver`, but for a sender waiting on the results, so sending to each subscription
+/// gives up once the retry policy's `sync_budget` has passed.
+pub async fn deliver_now(
+    server_state: &ServerState,
+    channel_id: &str,
+    payload: &MessagePayload,
+) -> Result<Vec<MessageResult>> {
+    let deadline = Instant::now() + server_state.retry_policy.sync_budget;
+    deliver_before(server_state, channel_id, payload, Some(deadline)).await
+}
+
+/// Send a message to every active subscription of a channel, retrying transient failures
+/// and timing out attempts at the deadline, if any.
+async fn deliver_before(
+    server_state: &ServerState,
+    channel_id: &str,
+    payload: &MessagePayload,
+    deadline: Option<Instant>,
+) -> Result<Vec<MessageResult>> {
+    let subscriptions = server_state.db().list_subscriptions(channel_id).await?;
+
+    // Subscriptions stored before duplicates were removed on subscribing may share an
+    // endpoint, which should only be pushed to once.
+    let mut endpoints = HashSet::new();
+    let subscriptions = subscriptions
+        .into_iter()
+        .filter(|subscription| endpoints.insert(subscription.value.endpoint.clone()));
+
+    let message_result = stream::iter(subscriptions)
+        .map(|subscription| {
+            deliver_to_subscription(server_state, channel_id, payload, subscription, deadline)
+        })
+        .buffered(MAX_CONCURRENT_PUSHES)
+        .collect()
+        .await;
+
+    Ok(message_result)
+}
+
+/// A stored message waiting to be delivered by a background worker.
+pub struct DeliveryJob {
+    pub channel_id: String,
+    pub message_id: String,
+    pub message: Message,
+    pub payload: MessagePayload,
+}
+
+/// Queue of messages to be delivered by a pool of background workers. Once a message
+/// has been delivered, its stored result is updated.
+#[derive(Clone)]
+pub struct DeliveryQueue {
+    sender: mpsc::Sender<DeliveryJob>,
+}
+
+impl DeliveryQueue {
+    /// Spawn `workers` tasks to deliver queued messages.
+    pub fn start(server_state: ServerState, workers: usize) -> Self {
+        let (sender, receiver) = mpsc::channel(DELIVERY_QUEUE_CAPACITY);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for _ in 0..workers {
+            tokio::spawn(delivery_worker(server_state.clone(), receiver.clone()));
+        }
+
+        DeliveryQueue { sender }
+    }
+
+    /// Returns true if there is room in the queue for another message.
+    pub fn has_capacity(&self) -> bool {
+        self.sender.capacity() > 0
+    }
+
+    pub fn enqueue(&self, job: DeliveryJob) -> Result<()> {
+        self.sender.try_send(job).map_err(|e| match e {
+            TrySendError::Full(_) => anyhow::anyhow!("Delivery queue is full."),
+            TrySendError::Closed(_) => anyhow::anyhow!("Delivery queue is closed."),
+        })
+    }
+}
+
+async fn delivery_worker(
+    server_state: ServerState,
+    receiver: Arc<Mutex<mpsc::Receiver<DeliveryJob>>>,
+) {
+    loop {
+        let job = receiver.lock().await.recv().await;
+
+        match job {
+            Some(job) => deliver_job(&server_state, job).await,
+            None => return,
+        }
+    }
+}
+
+/// Deliver a stored message and record its final status and results.
+async fn deliver_job(server_state: &ServerState, mut job: DeliveryJob) {
+    let channel_id = &job.channel_id;
+    let message_id = &job.message_id;
+
+    match deliver(server_state, channel_id, &job.payload).await {
+        Ok(message_result) => {
+            tracing::info!(%channel_id, %message_id, ?message_result, "Message sent.");
+            job.message.status = MessageStatus::Sent;
+            job.message.result = message_result;
+        }
+        Err(error) => {
+            tracing::error!(?error, %channel_id, %message_id, "Could not deliver message.");
+            job.message.status = MessageStatus::Failed;
+        }
+    }
+
+    if let Err(error) = server_state
+        .db()
+        .update_message(channel_id, message_id, &job.message)
+        .await
+    {
+        tracing::error!(?error, %channel_id, %message_id, "Could not store message result.");
+    }
+}
+
+/// Send a notice from the server itself to a channel's subscribers, and add it to the
+/// channel's messages like any other.
+pub async fn send_notice(
+    server_state: &ServerState,
+    channel_id: &str,
+    title: &str,
+    body: &str,
+    priority: Priority,
+) -> Result<()> {
+    let db = server_state.db();
+    let channel = db.get_channel(channel_id).await?;
+
+    let mut payload = MessagePayload::notice(
+        title,
+        body,
+        priority,
+        channel_id,
+        &server_state.default_action_url(channel_id, &channel),
+    )
+    .map_err(|errors| anyhow::anyhow!("Invalid notice: {:?}", errors))?;
+    payload.apply_channel_defaults(&channel);
+
+    let result = deliver(server_state, channel_id, &payload).await?;
+    tracing::info!(%channel_id, %title, message_result=?result, "Notice sent.");
+
+    db.create_message(
+        channel_id,
+        &Message {
+            message: payload.message,
+            title: payload.title,
+            body: payload.body,
+            actions: Vec::new(),
+            priority: Some(priority),
+            // There is no sender to record.
+            sender_ip: String::new(),
+            message_time: Utc::now(),
+            result,
+            status: MessageStatus::Sent,
+            deliver_at: None,
+        },
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Deliver scheduled messages as they become due, and notify channels whose heartbeat
+/// is overdue. Runs until the server exits.
+pub async fn run_scheduler(server_state: ServerState) {
+    let mut interval = tokio::time::interval(Duration::from_secs(SCHEDULER_INTERVAL_SECS));
+
+    loop {
+        interval.tick().await;
+
+        if let Err(error) = deliver_due_messages(&server_state).await {
+            tracing::error!(?error, "Could not deliver scheduled messages.");
+        }
+
+        if let Err(error) = check_heartbeats(&server_state).await {
+            tracing::error!(?error, "Could not check heartbeats.");
+        }
+    }
+}
+
+/// Notify the subscribers of each channel whose heartbeat has been missed, returning the
+/// number of channels notified.
+pub async fn check_heartbeats(server_state: &ServerState) -> Result<usize> {
+    let db = server_state.db();
+    let mut notified = 0;
+
+    for NamedRecord {
+        id: channel_id,
+        value: heartbeat,
+    } in db.list_overdue_heartbeats(Utc::now()).await?
+    {
+        // Marking the heartbeat first means subscribers are told once, even with several
+        // servers running.
+        if !db
+            .mark_heartbeat_missed(&channel_id, heartbeat.deadline)
+            .await?
+        {
+            continue;
+        }
+
+        let body = format!(
+            "No heartbeat since {}. One is expected every {} seconds.",
+            heartbeat.last_ping.format("%Y-%m-%d %H:%M:%S UTC"),
+            heartbeat.interval_secs
+        );
+        match send_notice(
+            server_state,
+            &channel_id,
+            "Missed heartbeat",
+            &body,
+            Priority::High,
+        )
+        .await
+        {
+            Ok(()) => notified += 1,
+            Err(error) => {
+                tracing::error!(?error, %channel_id, "Could not send missed heartbeat notice.");
+            }
+        }
+    }
+
+    Ok(notified)
+}
+
+/// Deliver every scheduled message that is due, returning the number delivered.
+pub async fn deliver_due_messages(server_state: &ServerState) -> Result<usize> {
+    let db = server_state.db();
+    let mut delivered = 0;
+
+    for scheduled in db.list_due_scheduled_messages(Utc::now()).await? {
+        let channel_id = &scheduled.channel_id;
+        let message_id = &scheduled.message_id;
+
+        // Taking the message before delivering it means that it is delivered at most once,
+        // even with several servers running, at the cost of it being lost if the server
+        // stops while delivering it.
+        if !db.take_scheduled_message(channel_id, message_id).await? {
+            continue;
+        }
+
+        match scheduled_job(db, &scheduled).await {
+            Ok(job) => {
+                deliver_job(server_state, job).await;
+                delivered += 1;
+            }
+            Err(error) => {
+                tracing::error!(?error, %channel_id, %message_id, "Could not load scheduled message.");
+                fail_message(db, channel_id, message_id).await;
+            }
+        }
+    }
+
+    Ok(delivered)
+}
+
+/// Load the stored message and payload of a scheduled message, ready to deliver.
+async fn scheduled_job(
+    db: &dyn NotifyDatabase,
+    scheduled: &ScheduledMessage,
+) -> Result<DeliveryJob> {
+    let payload = MessagePayload::from_stored(&scheduled.payload)?;
+    let message = db
+        .get_message(&scheduled.channel_id, &scheduled.message_id)
+        .await?;
+
+    Ok(DeliveryJob {
+        channel_id: scheduled.channel_id.clone(),
+        message_id: scheduled.message_id.clone(),
+        message,
+        payload,
+    })
+}
+
+/// Mark a message that has been taken from the schedule, but can't be delivered, as
+/// failed, so that it isn't left pending with nothing left to deliver it.
+async fn fail_message(db: &dyn NotifyDatabase, channel_id: &str, message_id: &str) {
+    let result = async {
+        let mut message = db.get_message(channel_id, message_id).await?;
+        message.status = MessageStatus::Failed;
+        db.update_message(channel_id, message_id, &message).await
+    }
+    .await;
+
+    if let Err(error) = result {
+        tracing::error!(?error, %channel_id, %message_id, "Could not mark message as failed.");
+    }
+}
+
+async fn deliver_to_subscription(
+    server_state: &ServerState,
+    channel_id: &str,
+    payload: &MessagePayload,
+    subscription: NamedRecord<Subscription>,
+    deadline: Option<Instant>,
+) -> MessageResult {
+    let policy = &server_state.retry_policy;
+    let mut attempts = 0;
+
+    let result_status = loop {
+        attempts += 1;
+
+        let mut time_limit = Duration::from_secs(TIMEOUT_SECS);
+        if let Some(deadline) = deadline {
+            time_limit = time_limit.min(deadline.saturating_duration_since(Instant::now()));
+        }
+
+        let result = timeout(
+            time_limit,
+            send_message(
+                &server_state.push_client,
+                payload,
+                &subscription.value,
+                &server_state.vapid_privkey,
+            ),
+        )
+        .await;
+
+        let (result_status, failure) = match result {
+            Ok(Ok(_)) => break "201".to_string(),
+            Ok(Err(e)) => (e.to_string(), SendFailure::classify(&e)),
+            Err(_) => ("Timed out.".to_string(), SendFailure::Transient(None)),
+        };
+
+        match failure {
+            SendFailure::Expired(reason) => {
+                deactivate_subscription(server_state, channel_id, &subscription.id, reason).await;
+                break result_status;
+            }
+            SendFailure::Transient(retry_after) => {
+                let delay = policy.delay_after(attempts, retry_after).filter(|delay| {
+                    deadline.is_none_or(|deadline| Instant::now() + *delay < deadline)
+                });
+                if let Some(delay) = delay {
+                    tracing::info!(
+                        %channel_id,
+                        subscription_id = %subscription.id,
+                        %result_status,
+                        ?delay,
+                        "Retrying push."
+                    );
+                    sleep(delay).await;
+                } else {
+                    break result_status;
+                }
+            }
+            SendFailure::Permanent => break result_status,
+        }
+    };
+
+    let endpoint_domain = Uri::from_str(&subscription.value.endpoint)
+        .ok()
+        .and_then(|d| d.authority().map(|d| d.to_string()))
+        .unwrap_or_default();
+
+    MessageResult {
+        result_status,
+        endpoint_domain,
+        attempts,
+    }
+}
+
+/// Stop sending to a subscription that the push service has told us is gone.
+async fn deactivate_subscription(
+    server_state: &ServerState,
+    channel_id: &str,
+    subscription_id: &str,
+    reason: String,
+) {
+    tracing::info!(%channel_id, %subscription_id, %reason, "Deactivating expired subscription.");
+
+    let deactivation = Deactivation {
+        time: Utc::now(),
+        reason,
+    };
+
+    if let Err(error) = server_state
+        .db()
+        .deactivate_subscription(channel_id, subscription_id, &deactivation)
+        .await
+    {
+        tracing::error!(?error, %channel_id, %subscription_id, "Could not deactivate subscription.");
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::database::memory::MemoryDatabase;
+    use crate::model::{Channel, Heartbeat};
+    use axum::{
+        extract::Extension,
+        http::{HeaderMap, StatusCode},
+        routing::post,
+        AddExtensionLayer, Router,
+    };
+    use openssl::{
+        bn::BigNumContext,
+        ec::{EcGroup, EcKey, PointConversionForm},
+        nid::Nid,
+    };
+    use std::collections::VecDeque;
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::{Arc, Mutex};
+
+    /// Status code and Retry-After header of a response from the fake push service.
+    type ScriptedResponse = (u16, Option<&'static str>);
+
+    /// A push service that replies to each request with the next of a scripted list of
+    /// (status, Retry-After) responses, and records the headers of the requests it receives.
+    #[derive(Clone, Default)]
+    pub struct FakePushService {
+        responses: Arc<Mutex<VecDeque<ScriptedResponse>>>,
+        pub requests: Arc<Mutex<Vec<HeaderMap>>>,
+
+        /// How long to wait before replying to each request.
+        delay: Duration,
+    }
+
+    async fn handle_push(
+        Extension(service): Extension<FakePushService>,
+        headers: HeaderMap,
+    ) -> (StatusCode, HeaderMap) {
+        service.requests.lock().unwrap().push(headers);
+        sleep(service.delay).await;
+        let (status, retry_after) = service
+            .responses
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or((201, None));
+
+        let mut response_headers = HeaderMap::new();
+        if let Some(retry_after) = retry_after {
+            response_headers.insert("retry-after", retry_after.parse().unwrap());
+        }
+
+        (StatusCode::from_u16(status).unwrap(), response_headers)
+    }
+
+    impl FakePushService {
+        /// Start the service, returning the endpoint URL of a subscription on it.
+        pub fn start(responses: Vec<ScriptedResponse>) -> (Self, String) {
+            Self::start_with_delay(responses, Duration::ZERO)
+        }
+
+        /// Start a service that waits for `delay` before replying to each request.
+        pub fn start_with_delay(
+            responses: Vec<ScriptedResponse>,
+            delay: Duration,
+        ) -> (Self, String) {
+            let service = FakePushService {
+                responses: Arc::new(Mutex::new(responses.into())),
+                delay,
+                ..FakePushService::default()
+            };
+
+            let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+            let endpoint = format!("http://{}/push", listener.local_addr().unwrap());
+
+            let router = Router::new()
+                .route("/push", post(handle_push))
+                .layer(AddExtensionLayer::new(service.clone()));
+            tokio::spawn(
+                axum::Server::from_tcp(listener)
+                    .unwrap()
+                    .serve(router.into_make_service()),
+            );
+
+            (service, endpoint)
+        }
+    }
+
+    fn generate_key() -> EcKey<openssl::pkey::Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        EcKey::generate(&group).unwrap()
+    }
+
+    /// Server state with a real VAPID key, backed by an in-memory database.
+    pub fn test_server_state() -> ServerState {
+        ServerState::new(
+            Arc::new(MemoryDatabase::new()),
+            "http://notify.test".to_string(),
+            "test-pubkey".to_string(),
+            generate_key().private_key_to_der().unwrap(),
+        )
+    }
+
+    /// A subscription with valid encryption keys, pointing at the given endpoint.
+    pub fn test_subscription(endpoint: &str) -> Subscription {
+        let key = generate_key();
+        let mut ctx = BigNumContext::new().unwrap();
+        let p256dh = key
+            .public_key()
+            .to_bytes(key.group(), PointConversionForm::UNCOMPRESSED, &mut ctx)
+            .unwrap();
+
+        Subscription {
+            endpoint: endpoint.to_string(),
+            p256dh: base64::encode_config(&p256dh, base64::URL_SAFE_NO_PAD),
+            auth: base64::encode_config([7u8; 16], base64::URL_SAFE_NO_PAD),
+            deactivated: None,
+        }
+    }
+
+    /// Create a channel with a single subscription, returning the channel ID.
+    pub async fn channel_with_subscription(server_state: &ServerState, endpoint: &str) -> String {
+        let db = server_state.db();
+        let channel_id = db
+            .create_channel(&Channel {
+                created: Utc::now(),
+                created_agent: "test-agent".to_string(),
+                created_ip: "127.0.0.1".to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        db.put_subscription(&channel_id, "sub1", &test_subscription(endpoint))
+            .await
+            .unwrap();
+
+        channel_id
+    }
+
+    fn fast_retries() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_secs(2),
+            sync_budget: Duration::from_secs(2),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deliver() {
+        let (service, endpoint) = FakePushService::start(vec![]);
+        let server_state = test_server_state();
+        let channel_id = channel_with_subscription(&server_state, &endpoint).await;
+
+        let payload =
+            MessagePayload::parse_new("hello", &channel_id, "http://notify.test/c/x").unwrap();
+        let result = deliver(&server_state, &channel_id, &payload).await.unwrap();
+
+        assert_eq!(1, result.len());
+        assert_eq!("201", result[0].result_status);
+        assert_eq!(1, result[0].attempts);
+        assert_eq!(1, service.requests.lock().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_deliver_sets_push_options() {
+        let (service, endpoint) = FakePushService::start(vec![]);
+        let server_state = test_server_state();
+        let channel_id = channel_with_subscription(&server_state, &endpoint).await;
+
+        let payload = MessagePayload::parse_new(
+            "message=hello&ttl=60&urgency=high&topic=status",
+            &channel_id,
+            "http://notify.test/c/x",
+        )
+        .unwrap();
+        deliver(&server_state, &channel_id, &payload).await.unwrap();
+
+        let requests = service.requests.lock().unwrap();
+        assert_eq!("60", requests[0]["ttl"]);
+        assert_eq!("high", requests[0]["urgency"]);
+        assert_eq!("status", requests[0]["topic"]);
+    }
+
+    #[tokio::test]
+    async fn test_deliver_maps_priority_to_push_options() {
+        let (service, endpoint) = FakePushService::start(vec![]);
+        let server_state = test_server_state();
+        let channel_id = channel_with_subscription(&server_state, &endpoint).await;
+
+        let payload = MessagePayload::parse_new(
+            "message=hello&priority=min&ttl=60",
+            &channel_id,
+            "http://notify.test/c/x",
+        )
+        .unwrap();
+        deliver(&server_state, &channel_id, &payload).await.unwrap();
+
+        for priority in ["high", "urgent"] {
+            let payload = MessagePayload::parse_new(
+                &format!("message=hello&priority={}", priority),
+                &channel_id,
+                "http://notify.test/c/x",
+            )
+            .unwrap();
+            deliver(&server_state, &channel_id, &payload).await.unwrap();
+        }
+
+        let requests = service.requests.lock().unwrap();
+        assert_eq!("60", requests[0]["ttl"]);
+        assert_eq!("very-low", requests[0]["urgency"]);
+
+        // Urgent messages are kept by the push service for longer than high priority ones.
+        assert_eq!("high", requests[1]["urgency"]);
+        assert_eq!("604800", requests[1]["ttl"]);
+        assert_eq!("high", requests[2]["urgency"]);
+        assert_eq!("2419200", requests[2]["ttl"]);
+    }
+
+    /// Store a message scheduled to be delivered at the given time, returning its ID.
+    async fn schedule_message(
+        db: &dyn NotifyDatabase,
+        channel_id: &str,
+        deliver_at: chrono::DateTime<Utc>,
+        payload: String,
+    ) -> String {
+        let message = Message {
+            message: "hello".to_string(),
+            sender_ip: "127.0.0.1".to_string(),
+            message_time: Utc::now(),
+            status: MessageStatus::Pending,
+            deliver_at: Some(deliver_at),
+            ..Default::default()
+        };
+        let message_id = db.create_message(channel_id, &message).await.unwrap();
+        db.create_scheduled_message(&ScheduledMessage {
+            channel_id: channel_id.to_string(),
+            message_id: message_id.clone(),
+            deliver_at,
+            payload,
+        })
+        .await
+        .unwrap();
+
+        message_id
+    }
+
+    #[tokio::test]
+    async fn test_deliver_due_messages() {
+        let (service, endpoint) = FakePushService::start(vec![]);
+        let server_state = test_server_state();
+        let channel_id = channel_with_subscription(&server_state, &endpoint).await;
+        let db = server_state.db();
+
+        let payload =
+            MessagePayload::parse_new("hello", &channel_id, "http://notify.test/c/x").unwrap();
+        for delay in [-1, 60] {
+            let deliver_at = Utc::now() + chrono::Duration::minutes(delay);
+            schedule_message(db, &channel_id, deliver_at, payload.to_stored().unwrap()).await;
+        }
+
+        // Only the message that is due is delivered, and only once.
+        assert_eq!(1, deliver_due_messages(&server_state).await.unwrap());
+        assert_eq!(0, deliver_due_messages(&server_state).await.unwrap());
+        assert_eq!(1, service.requests.lock().unwrap().len());
+
+        let messages = db.list_messages(&channel_id, 0, 2).await.unwrap();
+        let statuses: Vec<_> = messages.iter().map(|message| message.status).collect();
+        assert!(statuses.contains(&MessageStatus::Sent));
+        assert!(statuses.contains(&MessageStatus::Pending));
+        let sent = messages
+            .iter()
+            .find(|message| message.status == MessageStatus::Sent)
+            .unwrap();
+        assert_eq!("201", sent.result[0].result_status);
+    }
+
+    #[tokio::test]
+    async fn test_deliver_due_messages_records_failures() {
+        let (_, endpoint) = FakePushService::start(vec![(500, None); 3]);
+        let mut server_state = test_server_state();
+        server_state.retry_policy = fast_retries();
+        let channel_id = channel_with_subscription(&server_state, &endpoint).await;
+        let db = server_state.db();
+
+        let deliver_at = Utc::now() - chrono::Duration::minutes(1);
+        let payload =
+            MessagePayload::parse_new("hello", &channel_id, "http://notify.test/c/x").unwrap();
+        let rejected =
+            schedule_message(db, &channel_id, deliver_at, payload.to_stored().unwrap()).await;
+        let unreadable = schedule_message(db, &channel_id, deliver_at, "{".to_string()).await;
+
+        assert_eq!(1, deliver_due_messages(&server_state).await.unwrap());
+        assert_eq!(0, deliver_due_messages(&server_state).await.unwrap());
+
+        // Neither message is left pending once it has been taken from the schedule.
+        let message = db.get_message(&channel_id, &rejected).await.unwrap();
+        assert_eq!(MessageStatus::Sent, message.status);
+        assert_ne!("201", message.result[0].result_status);
+        assert_eq!(3, message.result[0].attempts);
+
+        let message = db.get_message(&channel_id, &unreadable).await.unwrap();
+        assert_eq!(MessageStatus::Failed, message.status);
+        assert!(message.result.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_check_heartbeats() {
+        let (service, endpoint) = FakePushService::start(vec![]);
+        let server_state = test_server_state();
+        let db = server_state.db();
+
+        let now = Utc::now();
+        let overdue = channel_with_subscription(&server_state, &endpoint).await;
+        let on_time = channel_with_subscription(&server_state, &endpoint).await;
+        for (channel_id, deadline) in [
+            (&overdue, now),
+            (&on_time, now + chrono::Duration::hours(1)),
+        ] {
+            let heartbeat = Heartbeat {
+                interval_secs: 3600,
+                grace_secs: 60,
+                last_ping: deadline - chrono::Duration::seconds(3660),
+                deadline,
+                missed: false,
+            };
+            db.put_heartbeat(channel_id, &heartbeat).await.unwrap();
+        }
+
+        // Subscribers are told about a missed heartbeat once.
+        assert_eq!(1, check_heartbeats(&server_state).await.unwrap());
+        assert_eq!(0, check_heartbeats(&server_state).await.unwrap());
+        assert_eq!(1, service.requests.lock().unwrap().len());
+
+        assert!(db.get_heartbeat(&overdue).await.unwrap().unwrap().missed);
+        let messages = db.list_messages(&overdue, 0, 10).await.unwrap();
+        assert_eq!(1, messages.len());
+        assert_eq!(Some("Missed heartbeat"), messages[0].title.as_deref());
+        assert_eq!(Some(Priority::High), messages[0].priority);
+        assert_eq!("201", messages[0].result[0].result_status);
+        assert!(db.list_messages(&on_time, 0, 10).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_deliver_retries_transient_failures() {
+        let (service, endpoint) = FakePushService::start(vec![(503, None), (429, Some("1"))]);
+        let mut server_state = test_server_state();
+        server_state.retry_policy = fast_retries();
+        let channel_id = channel_with_subscription(&server_state, &endpoint).await;
+
+        let payload =
+            MessagePayload::parse_new("hello", &channel_id, "http://notify.test/c/x").unwrap();
+        let result = deliver(&server_state, &channel_id, &payload).await.unwrap();
+
+        assert_eq!("201", result[0].result_status);
+        assert_eq!(3, result[0].attempts);
+        assert_eq!(3, service.requests.lock().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_deliver_gives_up_after_max_attempts() {
+        let (_, endpoint) = FakePushService::start(vec![(500, None); 5]);
+        let mut server_state = test_server_state();
+        server_state.retry_policy = fast_retries();
+        let channel_id = channel_with_subscription(&server_state, &endpoint).await;
+
+        let payload =
+            MessagePayload::parse_new("hello", &channel_id, "http://notify.test/c/x").unwrap();
+        let result = deliver(&server_state, &channel_id, &payload).await.unwrap();
+
+        assert_ne!("201", result[0].result_status);
+        assert_eq!(3, result[0].attempts);
+    }
+
+    #[tokio::test]
+    async fn test_deliver_now_limits_retries() {
+        let (service, endpoint) = FakePushService::start(vec![(503, Some("1")), (503, None)]);
+        let mut server_state = test_server_state();
+        server_state.retry_policy = RetryPolicy {
+            sync_budget: Duration::from_millis(500),
+            ..fast_retries()
+        };
+        let channel_id = channel_with_subscription(&server_state, &endpoint).await;
+
+        let payload =
+            MessagePayload::parse_new("hello", &channel_id, "http://notify.test/c/x").unwrap();
+
+        // Waiting as long as the push service asks would take longer than the budget.
+        let result = deliver_now(&server_state, &channel_id, &payload)
+            .await
+            .unwrap();
+        assert_ne!("201", result[0].result_status);
+        assert_eq!(1, result[0].attempts);
+
+        // Retries that fit within the budget are still made.
+        let result = deliver_now(&server_state, &channel_id, &payload)
+            .await
+            .unwrap();
+        assert_eq!("201", result[0].result_status);
+        assert_eq!(2, result[0].attempts);
+        assert_eq!(3, service.requests.lock().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_deliver_now_times_out_at_budget() {
+        let (_, endpoint) =
+            FakePushService::start_with_delay(vec![(201, None)], Duration::from_secs(5));
+        let mut server_state = test_server_state();
+        server_state.retry_policy = RetryPolicy {
+            sync_budget: Duration::from_millis(200),
+            ..fast_retries()
+        };
+        let channel_id = channel_with_subscription(&server_state, &endpoint).await;
+
+        let payload =
+            MessagePayload::parse_new("hello", &channel_id, "http://notify.test/c/x").unwrap();
+
+        // The attempt is cut short at the budget rather than running to the full timeout.
+        let start = Instant::now();
+        let result = deliver_now(&server_state, &channel_id, &payload)
+            .await
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!("Timed out.", result[0].result_status);
+        assert_eq!(1, result[0].attempts);
+    }
+
+    #[tokio::test]
+    async fn test_deliver_deactivates_expired_subscriptions() {
+        let (_, endpoint) = FakePushService::start(vec![(410, None)]);
+        let server_state = test_server_state();
+        let channel_id = channel_with_subscription(&server_state, &endpoint).await;
+
+        let payload =
+            MessagePayload::parse_new("hello", &channel_id, "http://notify.test/c/x").unwrap();
+        let result = deliver(&server_state, &channel_id, &payload).await.unwrap();
+
+        assert_eq!(1, result[0].attempts);
+        assert!(server_state
+            .db()
+            .list_subscriptions(&channel_id)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5),
+            ..RetryPolicy::default()
+        };
+
+        assert_eq!(Some(Duration::from_secs(1)), policy.delay_after(1, None));
+        assert_eq!(Some(Duration::from_secs(2)), policy.delay_after(2, None));
+        assert_eq!(Some(Duration::from_secs(4)), policy.delay_after(3, None));
+        assert_eq!(Some(Duration::from_secs(5)), policy.delay_after(4, None));
+        assert_eq!(None, policy.delay_after(5, None));
+    }
+
+    #[test]
+    fn test_backoff_honours_retry_after() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            ..RetryPolicy::default()
+        };
+
+        assert_eq!(
+            Some(Duration::from_secs(30)),
+            policy.delay_after(1, Some(Duration::from_secs(30)))
+        );
+        assert_eq!(None, policy.delay_after(1, Some(Duration::from_secs(120))));
+        assert_eq!(None, policy.delay_after(3, Some(Duration::from_secs(1))));
+    }
+}
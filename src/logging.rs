@@ -9,7 +9,7 @@ const LOG_MODULES: &[&str] = &["notify_run"];
 
 pub fn init_logging() {
     let mut env_filter = EnvFilter::default();
-    
+
     for module in LOG_MODULES {
         env_filter = env_filter.add_directive(
             format!("{}=info", module)
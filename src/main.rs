@@ -1,14 +1,21 @@
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use anyhow::Result;
 use clap::Parser;
+use database::{
+    firestore::FirestoreDatabase, memory::MemoryDatabase, postgres::PostgresDatabase,
+    sqlite::SqliteDatabase, NotifyDatabase,
+};
 use google_authz::{Credentials, TokenSource};
 use logging::init_logging;
 use migrate::migrate;
 use server::serve;
 use tiny_firestore_odm::Database;
 
+mod auth;
 mod database;
+mod delivery;
 mod logging;
 mod migrate;
 mod model;
@@ -31,6 +38,18 @@ enum SubCommand {
     Serve {
         #[clap(short, long)]
         port: Option<u16>,
+
+        /// Store data in a SQLite database at the given path instead of Firestore.
+        #[clap(long)]
+        sqlite: Option<PathBuf>,
+
+        /// Store data in the PostgreSQL database at the given connection URL instead of Firestore.
+        #[clap(long, conflicts_with = "sqlite")]
+        postgres: Option<String>,
+
+        /// Keep all data in memory, discarding it when the server exits.
+        #[clap(long, conflicts_with_all = &["sqlite", "postgres"])]
+        ephemeral: bool,
     },
 }
 
@@ -58,8 +77,23 @@ async fn main() -> Result<()> {
         SubCommand::Migrate { source } => {
             migrate(source, get_db().await).await?;
         }
-        SubCommand::Serve { port } => {
-            serve(port).await?;
+        SubCommand::Serve {
+            port,
+            sqlite,
+            postgres,
+            ephemeral,
+        } => {
+            let database: Arc<dyn NotifyDatabase> = if ephemeral {
+                Arc::new(MemoryDatabase::new())
+            } else if let Some(path) = sqlite {
+                Arc::new(SqliteDatabase::open(&path).await?)
+            } else if let Some(url) = postgres {
+                Arc::new(PostgresDatabase::connect(&url).await?)
+            } else {
+                Arc::new(FirestoreDatabase::new())
+            };
+
+            serve(port, database).await?;
         }
     }
 
@@ -4,7 +4,7 @@ use serde::Deserialize;
 use std::{collections::HashMap, fs::read_to_string, path::PathBuf};
 use tiny_firestore_odm::{Collection, Database};
 
-use crate::model::Channel;
+use crate::model::{Channel, CHANNELS_COLLECTION, SUBSCRIPTIONS_COLLECTION};
 
 #[derive(Deserialize)]
 struct DynamoExport {
@@ -54,7 +54,7 @@ struct Item {
 pub async fn migrate(path: PathBuf, db: Database) -> Result<()> {
     let migrate_json = read_to_string(path)?;
 
-    let channels: Collection<Channel> = db.collection("channels");
+    let channels: Collection<Channel> = db.collection(CHANNELS_COLLECTION);
 
     let migrate: DynamoExport = serde_json::from_str(&migrate_json)?;
 
@@ -69,6 +69,7 @@ pub async fn migrate(path: PathBuf, db: Database) -> Result<()> {
             created,
             created_agent: item.meta.value.agent.value,
             created_ip: item.meta.value.ip.value,
+            ..Default::default()
         };
 
         tracing::info!(%index, "Inserting channel.");
@@ -78,12 +79,13 @@ pub async fn migrate(path: PathBuf, db: Database) -> Result<()> {
         for (subscription_id, subscription) in item.subscriptions.value {
             let _span = tracing::info_span!("Subscription", %subscription_id).entered();
             let subscriptions: Collection<crate::model::Subscription> =
-                channels.subcollection(&channel_id, "subscriptions");
+                channels.subcollection(&channel_id, SUBSCRIPTIONS_COLLECTION);
 
             let sub = crate::model::Subscription {
                 endpoint: subscription.value.endpoint.value,
                 auth: subscription.value.keys.value.auth.value,
                 p256dh: subscription.value.keys.value.p256dh.value,
+                deactivated: None,
             };
 
             tracing::info!("Inserting subscription.");
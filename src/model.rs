@@ -1,38 +1,282 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::str::FromStr;
 
+pub const CHANNELS_COLLECTION: &str = "channels";
 pub const MESSAGES_COLLECTION: &str = "messages";
 pub const SUBSCRIPTIONS_COLLECTION: &str = "subscriptions";
+pub const IDEMPOTENCY_KEYS_COLLECTION: &str = "idempotency_keys";
+pub const SCHEDULED_MESSAGES_COLLECTION: &str = "scheduled_messages";
+pub const HEARTBEATS_COLLECTION: &str = "heartbeats";
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Subscription {
     pub endpoint: String,
     pub auth: String,
     pub p256dh: String,
+
+    /// Set once the push service has reported that the subscription no longer exists,
+    /// after which no more messages are sent to it.
+    #[serde(default)]
+    pub deactivated: Option<Deactivation>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Deactivation {
+    #[serde(with = "firestore_serde_timestamp::timestamp")]
+    pub time: DateTime<Utc>,
+
+    pub reason: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Channel {
     #[serde(with = "firestore_serde_timestamp::timestamp")]
     pub created: DateTime<Utc>,
 
     pub created_agent: String,
     pub created_ip: String,
+
+    /// Used for messages sent to the channel that don't set `vibrate` or `silent`.
+    #[serde(default)]
+    pub default_vibrate: bool,
+    #[serde(default)]
+    pub default_silent: bool,
+
+    /// SHA-256 hashes (hex-encoded) of the tokens required to send messages to the channel
+    /// and to read its messages or subscribe to it. The channel is open to anyone who knows
+    /// its ID for whichever of these is not set.
+    #[serde(default)]
+    pub send_token_hash: Option<String>,
+    #[serde(default)]
+    pub read_token_hash: Option<String>,
+
+    /// Display name and description, shown on the channel page.
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+
+    /// Used for messages sent to the channel that don't set `action` or `icon`. Without
+    /// a default action, clicking a notification opens the channel page.
+    #[serde(default)]
+    pub default_action: Option<String>,
+    #[serde(default)]
+    pub default_icon: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Message {
     pub message: String,
+
+    /// Notification title and body, if the sender gave them separately. Otherwise the
+    /// first line of `message` is used as the title.
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub body: Option<String>,
+
+    /// Buttons shown on the notification.
+    #[serde(default)]
+    pub actions: Vec<MessageAction>,
+
+    #[serde(default)]
+    pub priority: Option<Priority>,
+
     pub sender_ip: String,
 
     #[serde(with = "firestore_serde_timestamp::timestamp")]
     pub message_time: DateTime<Utc>,
 
     pub result: Vec<MessageResult>,
+
+    #[serde(default)]
+    pub status: MessageStatus,
+
+    /// Set if the sender asked for the message to be delivered later.
+    #[serde(default, with = "optional_timestamp")]
+    pub deliver_at: Option<DateTime<Utc>>,
+}
+
+/// Whether a message has been sent yet.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageStatus {
+    /// Scheduled to be delivered later.
+    Pending,
+
+    /// Waiting for a background worker to deliver it.
+    Queued,
+
+    /// Messages stored before scheduling was introduced were all sent immediately.
+    #[default]
+    Sent,
+
+    /// Was scheduled, but cancelled before it was delivered.
+    Cancelled,
+
+    /// Could not be delivered at all, so there are no results for its subscriptions.
+    Failed,
+}
+
+impl MessageStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageStatus::Pending => "pending",
+            MessageStatus::Queued => "queued",
+            MessageStatus::Sent => "sent",
+            MessageStatus::Cancelled => "cancelled",
+            MessageStatus::Failed => "failed",
+        }
+    }
+}
+
+impl FromStr for MessageStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(MessageStatus::Pending),
+            "queued" => Ok(MessageStatus::Queued),
+            "sent" => Ok(MessageStatus::Sent),
+            "cancelled" => Ok(MessageStatus::Cancelled),
+            "failed" => Ok(MessageStatus::Failed),
+            _ => Err(anyhow::anyhow!("Unknown message status: {}", s)),
+        }
+    }
+}
+
+/// A message waiting to be delivered at a later time.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScheduledMessage {
+    pub channel_id: String,
+    pub message_id: String,
+
+    #[serde(with = "firestore_serde_timestamp::timestamp")]
+    pub deliver_at: DateTime<Utc>,
+
+    /// The push payload to send, as stored by `MessagePayload::to_stored`.
+    pub payload: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// A channel's heartbeat: the channel is expected to be pinged every `interval_secs`,
+/// and its subscribers are notified if it is not.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Heartbeat {
+    pub interval_secs: u32,
+
+    /// Time allowed after a ping is due before it is considered missed.
+    pub grace_secs: u32,
+
+    #[serde(with = "firestore_serde_timestamp::timestamp")]
+    pub last_ping: DateTime<Utc>,
+
+    /// Time by which the next ping must arrive: `last_ping`, plus the interval and grace.
+    #[serde(with = "firestore_serde_timestamp::timestamp")]
+    pub deadline: DateTime<Utc>,
+
+    /// Set once subscribers have been told that a ping was missed, until pings resume.
+    pub missed: bool,
+}
+
+/// Like `firestore_serde_timestamp::timestamp`, for optional timestamps.
+mod optional_timestamp {
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    struct Timestamp(#[serde(with = "firestore_serde_timestamp::timestamp")] DateTime<Utc>);
+
+    pub fn serialize<S: Serializer>(
+        time: &Option<DateTime<Utc>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        time.map(Timestamp).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<DateTime<Utc>>, D::Error> {
+        Ok(Option::<Timestamp>::deserialize(deserializer)?.map(|Timestamp(time)| time))
+    }
+}
+
+/// How important a message is, which determines how it is delivered and shown.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Min,
+    Low,
+    Default,
+    High,
+    Urgent,
+}
+
+impl Priority {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Priority::Min => "min",
+            Priority::Low => "low",
+            Priority::Default => "default",
+            Priority::High => "high",
+            Priority::Urgent => "urgent",
+        }
+    }
+}
+
+impl FromStr for Priority {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "min" => Ok(Priority::Min),
+            "low" => Ok(Priority::Low),
+            "default" => Ok(Priority::Default),
+            "high" => Ok(Priority::High),
+            "urgent" => Ok(Priority::Urgent),
+            _ => Err(anyhow::anyhow!("Unknown priority: {}", s)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MessageAction {
+    pub label: String,
+
+    /// URL to open when the button is clicked.
+    pub url: String,
+}
+
+/// The message stored for a send with an idempotency key, so that repeats of the send
+/// can be answered without sending it again.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IdempotencyRecord {
+    /// Not set while the first send with the key is still being sent.
+    #[serde(default)]
+    pub message_id: Option<String>,
+
+    #[serde(with = "firestore_serde_timestamp::timestamp")]
+    pub time: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MessageResult {
     pub endpoint_domain: String,
     pub result_status: String,
+
+    /// Number of push requests made, including retries.
+    #[serde(default = "default_attempts")]
+    pub attempts: u32,
+}
+
+impl MessageResult {
+    /// Returns true if the push service accepted the message.
+    pub fn delivered(&self) -> bool {
+        self.result_status == "201"
+    }
+}
+
+/// Results stored before retries were introduced were always from a single attempt.
+fn default_attempts() -> u32 {
+    1
 }
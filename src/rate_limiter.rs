@@ -55,8 +55,7 @@ where
             let ip = req
                 .headers()
                 .get("x-forwarded-for")
-                .map(|d| d.to_str().ok())
-                .flatten()
+                .and_then(|d| d.to_str().ok())
                 .unwrap_or("unknown")
                 .to_owned();
 
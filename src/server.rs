@@ -1,22 +1,26 @@
-use crate::logging::LogError;
+use crate::auth::{generate_token, hash_token, RequestToken};
+use crate::database::NotifyDatabase;
+use crate::delivery::{deliver_now, run_scheduler, send_notice, DeliveryJob};
+use crate::logging::{LogError, WebResult};
 use crate::model::{
-    Channel, Message, MessageResult, Subscription, MESSAGES_COLLECTION, SUBSCRIPTIONS_COLLECTION,
+    Channel, Heartbeat, IdempotencyRecord, Message, MessageAction, MessageResult, MessageStatus,
+    Priority, ScheduledMessage, Subscription,
 };
 use crate::rate_limiter::RateLimiterMiddleware;
 use crate::server_state::ServerState;
-use crate::vapid::{send_message, MessagePayload};
-use axum::body::{Body, Bytes};
+use crate::vapid::{check_idempotency_key, is_http_url, MessagePayload, ValidationErrors};
+use axum::body::{box_body, Body, BoxBody, Bytes};
 use axum::extract::{ConnectInfo, TypedHeader};
-use axum::http::{Response, Uri};
+use axum::http::Response;
 use axum::{
-    extract::{Extension, Path},
-    routing::{get, post},
+    error_handling::HandleErrorExt,
+    extract::{Extension, Path, Query},
     http::StatusCode,
+    response::IntoResponse,
+    routing::{delete, get, post},
     AddExtensionLayer, Json, Router,
-    error_handling::HandleErrorExt,
 };
 use chrono::{DateTime, Utc};
-use futures::future::join_all;
 use governor::Quota;
 use headers::{HeaderMap, HeaderName, HeaderValue, UserAgent};
 use nonzero_ext::nonzero;
@@ -24,23 +28,73 @@ use qrcode::render::svg;
 use serde::{Deserialize, Serialize};
 use std::convert::Infallible;
 use std::net::SocketAddr;
-use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
-use tiny_firestore_odm::Collection;
-use tokio::time::timeout;
+use tokio::time::sleep;
 use tower::layer::layer_fn;
 use tower_http::services::ServeDir;
 use tower_http::services::ServeFile;
 
-/// Timeout (seconds) of external service when invoking push request.
-const TIMEOUT_SECS: u64 = 10;
-
 /// Rate limit on calls that access database.
 const MAX_REQUESTS_PER_MINUTE: u32 = 10;
 
+/// Number of background workers delivering messages sent asynchronously.
+const DELIVERY_WORKERS: usize = 4;
+
+/// Request header identifying a send, so that retries of it are only sent once.
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+/// Response header set when a send is answered from an earlier send with the same key.
+const IDEMPOTENT_REPLAYED: &str = "idempotent-replayed";
+
+/// How long (seconds) a send may hold its idempotency key before storing its message.
+/// Repeats of the send wait this long for it, then assume it failed and send the message
+/// themselves.
+const IDEMPOTENCY_PENDING_SECS: i64 = 30;
+
+/// How often (milliseconds) a repeat of a send still in progress checks whether it has
+/// finished.
+const IDEMPOTENCY_POLL_MS: u64 = 100;
+
+/// Shortest interval between heartbeat pings. Missed pings are only checked for every
+/// few seconds, so much shorter intervals would not be noticed in time.
+const MIN_HEARTBEAT_INTERVAL_SECS: u32 = 60;
+
+/// Longest interval between heartbeat pings, and longest grace period.
+const MAX_HEARTBEAT_INTERVAL_SECS: u32 = 30 * 24 * 60 * 60;
+
+/// Time allowed after a heartbeat ping is due, unless the channel sets its own.
+const DEFAULT_HEARTBEAT_GRACE_SECS: u32 = 5 * 60;
+
+/// Number of messages returned by the channel info endpoint, unless a limit is given.
+const DEFAULT_MESSAGE_PAGE_SIZE: u32 = 10;
+
+/// Maximum number of messages returned by a single call to the channel info endpoint.
+const MAX_MESSAGE_PAGE_SIZE: u32 = 100;
+
+/// Maximum length of a channel's display name.
+const MAX_CHANNEL_NAME_LENGTH: usize = 100;
+
+/// Maximum length of a channel's description.
+const MAX_CHANNEL_DESCRIPTION_LENGTH: usize = 1000;
+
 #[derive(Serialize)]
 struct MessageInfo {
     message: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    actions: Vec<MessageAction>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    priority: Option<Priority>,
+
+    status: MessageStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deliver_at: Option<DateTime<Utc>>,
+
     result: Vec<MessageResult>,
     time: DateTime<Utc>,
 }
@@ -59,28 +113,161 @@ struct ChannelInfo {
 
     endpoint: String,
     channel_page: String,
+
+    /// Tokens minted for a new channel. Only their hashes are stored, so they are only
+    /// ever returned when the channel is registered.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    send_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    read_token: Option<String>,
+
+    #[serde(flatten)]
+    metadata: ChannelMetadata,
+}
+
+/// Descriptive settings of a channel, which can be given when it is registered and
+/// changed later.
+#[derive(Serialize, Deserialize, Default)]
+struct ChannelMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    default_action: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    default_icon: Option<String>,
+}
+
+impl ChannelMetadata {
+    fn of(channel: &Channel) -> Self {
+        ChannelMetadata {
+            name: channel.name.clone(),
+            description: channel.description.clone(),
+            default_action: channel.default_action.clone(),
+            default_icon: channel.default_icon.clone(),
+        }
+    }
+
+    /// Check the fields that are given. Empty fields are allowed, since they clear the
+    /// channel's setting.
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = Vec::new();
+
+        let lengths = [
+            ("name", &self.name, MAX_CHANNEL_NAME_LENGTH),
+            (
+                "description",
+                &self.description,
+                MAX_CHANNEL_DESCRIPTION_LENGTH,
+            ),
+        ];
+        for (field, text, max_length) in lengths {
+            if text
+                .as_ref()
+                .is_some_and(|text| text.chars().count() > max_length)
+            {
+                errors.push(format!(
+                    "{}: must be at most {} characters",
+                    field, max_length
+                ));
+            }
+        }
+
+        let urls = [
+            ("default_action", &self.default_action),
+            ("default_icon", &self.default_icon),
+        ];
+        for (field, url) in urls {
+            if url
+                .as_deref()
+                .is_some_and(|url| !url.is_empty() && !is_http_url(url))
+            {
+                errors.push(format!("{}: must be an http or https URL", field));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ValidationErrors { errors })
+        }
+    }
+
+    /// Update a channel with the fields that are given, clearing those that are empty.
+    fn apply(self, channel: &mut Channel) {
+        let fields = [
+            (self.name, &mut channel.name),
+            (self.description, &mut channel.description),
+            (self.default_action, &mut channel.default_action),
+            (self.default_icon, &mut channel.default_icon),
+        ];
+        for (value, setting) in fields {
+            if let Some(value) = value {
+                *setting = Some(value).filter(|value| !value.is_empty());
+            }
+        }
+    }
+}
+
+/// Optional settings for a new channel, sent as a JSON body.
+#[derive(Deserialize, Default)]
+struct RegisterChannelRequest {
+    #[serde(default)]
+    default_vibrate: bool,
+    #[serde(default)]
+    default_silent: bool,
+
+    /// Require a token to send messages to the channel.
+    #[serde(default)]
+    send_token: bool,
+    /// Require a token to read the channel's messages or subscribe to it.
+    #[serde(default)]
+    read_token: bool,
+
+    #[serde(flatten)]
+    metadata: ChannelMetadata,
 }
 
 async fn register_channel(
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     TypedHeader(user_agent): TypedHeader<UserAgent>,
     server_state: Extension<ServerState>,
-) -> Result<Json<ChannelInfo>, StatusCode> {
-    let db = server_state.db().await.log_error_internal()?;
+    body: Bytes,
+) -> Result<Response<BoxBody>, StatusCode> {
     let ip: String = addr.ip().to_string();
 
-    let channels = db.channels();
+    let settings: RegisterChannelRequest = if body.is_empty() {
+        RegisterChannelRequest::default()
+    } else {
+        serde_json::from_slice(&body).log_error_bad_request()?
+    };
+    if let Err(errors) = settings.metadata.validate() {
+        return Ok((StatusCode::BAD_REQUEST, Json(errors))
+            .into_response()
+            .map(box_body));
+    }
 
-    let channel_id = channels
-        .create(&Channel {
-            created: Utc::now(),
-            created_agent: user_agent.to_string(),
-            created_ip: ip.clone(),
-        })
+    let send_token = settings.send_token.then(generate_token);
+    let read_token = settings.read_token.then(generate_token);
+
+    let mut channel = Channel {
+        created: Utc::now(),
+        created_agent: user_agent.to_string(),
+        created_ip: ip.clone(),
+        default_vibrate: settings.default_vibrate,
+        default_silent: settings.default_silent,
+        send_token_hash: send_token.as_deref().map(hash_token),
+        read_token_hash: read_token.as_deref().map(hash_token),
+        ..Default::default()
+    };
+    settings.metadata.apply(&mut channel);
+
+    let channel_id = server_state
+        .db()
+        .create_channel(&channel)
         .await
-        .log_error_internal()?
-        .leaf_name()
-        .to_string();
+        .log_error_internal()?;
 
     tracing::info!(%channel_id, %ip, "Channel created.");
 
@@ -91,34 +278,56 @@ async fn register_channel(
         endpoint: server_state.endpoint_url(&channel_id),
         channel_page: server_state.channel_page_url(&channel_id),
         channel_id,
-    }))
+        send_token,
+        read_token,
+        metadata: ChannelMetadata::of(&channel),
+    })
+    .into_response()
+    .map(box_body))
+}
+
+#[derive(Deserialize)]
+struct MessagePageQuery {
+    #[serde(default)]
+    offset: u32,
+    limit: Option<u32>,
 }
 
 async fn info(
     server_state: Extension<ServerState>,
     Path(channel_id): Path<String>,
+    Query(page): Query<MessagePageQuery>,
+    token: RequestToken,
 ) -> Result<Json<ChannelInfo>, StatusCode> {
-    let db = server_state.db().await.log_error_internal()?;
-
-    let channels = db.channels();
-    channels.get(&*channel_id).await.log_error_not_found()?;
+    let db = server_state.db();
+    let channel = db.get_channel(&channel_id).await.log_error_not_found()?;
+    token
+        .authorize(channel.read_token_hash.as_deref())
+        .log_error_forbidden()?;
 
-    let messages: Collection<Message> = channels.subcollection(&channel_id, MESSAGES_COLLECTION);
+    let limit = page
+        .limit
+        .unwrap_or(DEFAULT_MESSAGE_PAGE_SIZE)
+        .min(MAX_MESSAGE_PAGE_SIZE);
 
-    let messages = messages
-        .list()
-        .with_order_by("message_time desc")
-        .with_page_size(10)
-        .get_page()
-        .await;
+    let messages = db
+        .list_messages(&channel_id, page.offset, limit)
+        .await
+        .log_error_internal()?;
 
     Ok(Json(ChannelInfo {
         messages: messages
             .into_iter()
             .map(|d| MessageInfo {
-                message: d.value.message,
-                result: d.value.result,
-                time: d.value.message_time,
+                message: d.message,
+                title: d.title,
+                body: d.body,
+                actions: d.actions,
+                priority: d.priority,
+                status: d.status,
+                deliver_at: d.deliver_at,
+                result: d.result,
+                time: d.message_time,
             })
             .collect(),
         time: "".to_string(),
@@ -126,87 +335,613 @@ async fn info(
         endpoint: server_state.endpoint_url(&channel_id),
         channel_page: server_state.channel_page_url(&channel_id),
         channel_id,
+        send_token: None,
+        read_token: None,
+        metadata: ChannelMetadata::of(&channel),
     }))
 }
 
-async fn send_message_with_timeout(
-    payload: &MessagePayload,
-    subscription: Subscription,
-    privkey: &[u8],
-    duration: Duration,
-) -> MessageResult {
-    let result = timeout(duration, send_message(payload, &subscription, privkey)).await;
-
-    let result_status = match result {
-        Ok(Ok(_)) => "201".to_string(),
-        Ok(Err(e)) => e.to_string(),
-        Err(_) => "Timed out.".to_string(),
-    };
-
-    let endpoint_domain = Uri::from_str(&subscription.endpoint)
-        .ok()
-        .map(|d| d.authority().map(|d| d.to_string()))
-        .flatten()
-        .unwrap_or_default();
+/// Change a channel's descriptive settings. Fields that are not given are left unchanged.
+async fn update_channel(
+    server_state: Extension<ServerState>,
+    Path(channel_id): Path<String>,
+    token: RequestToken,
+    metadata: Json<ChannelMetadata>,
+) -> Result<Response<BoxBody>, StatusCode> {
+    let db = server_state.db();
+    let mut channel = db.get_channel(&channel_id).await.log_error_not_found()?;
+    if channel.send_token_hash.is_none() {
+        return Ok(send_token_required(&channel_id));
+    }
+    token
+        .authorize(channel.send_token_hash.as_deref())
+        .log_error_forbidden()?;
 
-    MessageResult {
-        result_status,
-        endpoint_domain,
+    if let Err(errors) = metadata.validate() {
+        return Ok((StatusCode::BAD_REQUEST, Json(errors))
+            .into_response()
+            .map(box_body));
     }
+
+    metadata.0.apply(&mut channel);
+    db.update_channel(&channel_id, &channel)
+        .await
+        .log_error_internal()?;
+
+    tracing::info!(%channel_id, "Channel updated.");
+
+    Ok(Json(ChannelMetadata::of(&channel))
+        .into_response()
+        .map(box_body))
+}
+
+#[derive(Deserialize)]
+struct SendQuery {
+    /// Respond once the message is stored, and deliver it in the background.
+    #[serde(rename = "async", default)]
+    asynchronous: bool,
+}
+
+#[derive(Serialize)]
+struct SendAccepted {
+    message_id: String,
+
+    /// Set if the message is scheduled to be delivered later.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deliver_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct SendResult {
+    endpoint_domain: String,
+    result_status: String,
+    delivered: bool,
+}
+
+#[derive(Serialize)]
+struct SendResponse {
+    message_id: String,
+
+    /// Number of subscriptions the message was sent to.
+    subscriptions: usize,
+    results: Vec<SendResult>,
+}
+
+/// Returns true if the request body is declared as JSON.
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get("content-type")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|media_type| media_type.trim().eq_ignore_ascii_case("application/json"))
+}
+
+/// Returns true if the client's Accept header lists JSON.
+fn accepts_json(headers: &HeaderMap) -> bool {
+    headers
+        .get_all("accept")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media_type| {
+            let media_type = media_type.split(';').next().unwrap_or_default().trim();
+            media_type.eq_ignore_ascii_case("application/json")
+        })
+}
+
+/// Returns true if the client asked for asynchronous processing with `Prefer: respond-async`.
+fn prefers_async(headers: &HeaderMap) -> bool {
+    headers
+        .get_all("prefer")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|preference| preference.trim().eq_ignore_ascii_case("respond-async"))
 }
 
 async fn send(
     server_state: Extension<ServerState>,
     Path(channel_id): Path<String>,
+    Query(query): Query<SendQuery>,
+    token: RequestToken,
+    headers: HeaderMap,
     message: String,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
-) -> Result<String, StatusCode> {
-    let db = server_state.db().await.log_error_internal()?;
+) -> Result<Response<BoxBody>, StatusCode> {
+    let db = server_state.db();
+    let channel = db.get_channel(&channel_id).await.log_error_not_found()?;
+    token
+        .authorize(channel.send_token_hash.as_deref())
+        .log_error_forbidden()?;
 
-    let channels = db.channels();
-    channels.get(&*channel_id).await.log_error_not_found()?;
+    let default_action = server_state.default_action_url(&channel_id, &channel);
+    let payload = if is_json(&headers) {
+        MessagePayload::parse_json(&message, &channel_id, &default_action)
+    } else {
+        MessagePayload::parse_new(&message, &channel_id, &default_action)
+    };
+    let mut payload = match payload {
+        Ok(payload) => payload,
+        Err(errors) => {
+            tracing::info!(%channel_id, ?errors, "Rejected invalid message.");
+            return Ok((StatusCode::BAD_REQUEST, Json(errors))
+                .into_response()
+                .map(box_body));
+        }
+    };
+
+    payload.apply_channel_defaults(&channel);
+
+    let idempotency_key = match idempotency_key(&headers, &payload) {
+        Ok(key) => key,
+        Err(errors) => {
+            return Ok((StatusCode::BAD_REQUEST, Json(errors))
+                .into_response()
+                .map(box_body));
+        }
+    };
+
+    // Store the full text, even if it has to be shortened to send.
+    let mut message = Message {
+        message: payload.message.to_string(),
+        title: payload.title.clone(),
+        body: payload.body.clone(),
+        actions: payload.data.actions.clone(),
+        priority: payload.priority,
+        message_time: Utc::now(),
+        sender_ip: addr.ip().to_string(),
+        result: Vec::new(),
+        status: MessageStatus::Sent,
+        deliver_at: None,
+    };
+
+    if let Err(errors) = payload.fit_size() {
+        tracing::info!(%channel_id, ?errors, "Rejected oversize message.");
+        return Ok((StatusCode::PAYLOAD_TOO_LARGE, Json(errors))
+            .into_response()
+            .map(box_body));
+    }
+
+    if let Some(key) = &idempotency_key {
+        if let Some(message_id) = claim_idempotency_key(&server_state, &channel_id, key).await? {
+            tracing::info!(%channel_id, %message_id, "Replaying idempotent send.");
+            let message = db
+                .get_message(&channel_id, &message_id)
+                .await
+                .log_error_internal()?;
+
+            let mut response = send_response(&headers, message_id, message);
+            response
+                .headers_mut()
+                .insert(IDEMPOTENT_REPLAYED, HeaderValue::from_static("true"));
+            return Ok(response);
+        }
+    }
+
+    if let Some(deliver_at) = payload.deliver_at.filter(|time| *time > Utc::now()) {
+        message.status = MessageStatus::Pending;
+        message.deliver_at = Some(deliver_at);
+
+        let message_id = db
+            .create_message(&channel_id, &message)
+            .await
+            .log_error_internal()?;
+        db.create_scheduled_message(&ScheduledMessage {
+            channel_id: channel_id.clone(),
+            message_id: message_id.clone(),
+            deliver_at,
+            payload: payload.to_stored().log_error_internal()?,
+        })
+        .await
+        .log_error_internal()?;
+        remember_idempotency_key(db, &channel_id, idempotency_key, &message_id).await;
+
+        tracing::info!(%channel_id, %message_id, %deliver_at, "Message scheduled.");
+
+        return Ok(send_response(&headers, message_id, message));
+    }
+
+    if query.asynchronous || prefers_async(&headers) {
+        if let Some(queue) = &server_state.delivery_queue {
+            if !queue.has_capacity() {
+                tracing::warn!(%channel_id, "Delivery queue is full.");
+                return Err(StatusCode::SERVICE_UNAVAILABLE);
+            }
+
+            message.status = MessageStatus::Queued;
+            let message_id = db
+                .create_message(&channel_id, &message)
+                .await
+                .log_error_internal()?;
+            remember_idempotency_key(db, &channel_id, idempotency_key, &message_id).await;
+
+            let job = DeliveryJob {
+                channel_id: channel_id.clone(),
+                message_id: message_id.clone(),
+                message: message.clone(),
+                payload,
+            };
+            if let Err(error) = queue.enqueue(job) {
+                // Nothing else will deliver the stored message, so don't leave it queued.
+                message.status = MessageStatus::Failed;
+                db.update_message(&channel_id, &message_id, &message)
+                    .await
+                    .log_error_internal()?;
+                return Err(error).log_error_internal();
+            }
+
+            return Ok(send_response(&headers, message_id, message));
+        }
+    }
 
     // Send to subscriptions.
+    message.result = deliver_now(&server_state, &channel_id, &payload)
+        .await
+        .log_error_internal()?;
 
-    let subscriptions: Collection<Subscription> =
-        channels.subcollection(&channel_id, SUBSCRIPTIONS_COLLECTION);
+    tracing::info!(%channel_id, message_result=?message.result, "Message sent.");
 
-    let payload = MessagePayload::parse_new(
-        &message,
-        &*channel_id,
-        &server_state.channel_page_url(&*channel_id),
-    );
-    // let mut message_result = Vec::new();
-    let mut futures = Vec::new();
+    // Store message.
+    let message_id = db
+        .create_message(&channel_id, &message)
+        .await
+        .log_error_internal()?;
+    remember_idempotency_key(db, &channel_id, idempotency_key, &message_id).await;
 
-    let subscriptions = subscriptions.list().with_page_size(10).get_page().await;
-    for subscription in subscriptions {
-        futures.push(send_message_with_timeout(
-            &payload,
-            subscription.value,
-            &server_state.vapid_privkey,
-            Duration::from_secs(TIMEOUT_SECS),
+    Ok(send_response(&headers, message_id, message))
+}
+
+/// Response to a message that has been sent: its results as JSON if the client accepts
+/// it, or otherwise just "ok". A message scheduled or queued to be delivered later is
+/// reported as accepted.
+fn send_response(headers: &HeaderMap, message_id: String, message: Message) -> Response<BoxBody> {
+    if matches!(
+        message.status,
+        MessageStatus::Pending | MessageStatus::Queued
+    ) {
+        let accepted = SendAccepted {
+            message_id,
+            deliver_at: message.deliver_at,
+        };
+        return (StatusCode::ACCEPTED, Json(accepted))
+            .into_response()
+            .map(box_body);
+    }
+
+    if !accepts_json(headers) {
+        return "ok".into_response().map(box_body);
+    }
+
+    let response = SendResponse {
+        message_id,
+        subscriptions: message.result.len(),
+        results: message
+            .result
+            .into_iter()
+            .map(|result| SendResult {
+                delivered: result.delivered(),
+                endpoint_domain: result.endpoint_domain,
+                result_status: result.result_status,
+            })
+            .collect(),
+    };
+
+    Json(response).into_response().map(box_body)
+}
+
+/// Cancel a message scheduled to be delivered later.
+async fn cancel(
+    server_state: Extension<ServerState>,
+    Path((channel_id, message_id)): Path<(String, String)>,
+    token: RequestToken,
+) -> Result<Json<()>, StatusCode> {
+    let db = server_state.db();
+    let channel = db.get_channel(&channel_id).await.log_error_not_found()?;
+    token
+        .authorize(channel.send_token_hash.as_deref())
+        .log_error_forbidden()?;
+    let mut message = db
+        .get_message(&channel_id, &message_id)
+        .await
+        .log_error_not_found()?;
+
+    let taken = db
+        .take_scheduled_message(&channel_id, &message_id)
+        .await
+        .log_error_internal()?;
+    if !taken {
+        // Already delivered or cancelled.
+        return Err(StatusCode::CONFLICT);
+    }
+
+    message.status = MessageStatus::Cancelled;
+    db.update_message(&channel_id, &message_id, &message)
+        .await
+        .log_error_internal()?;
+
+    tracing::info!(%channel_id, %message_id, "Scheduled message cancelled.");
+
+    Ok(Json(()))
+}
+
+/// The idempotency key of a send, from the `Idempotency-Key` header or otherwise the
+/// `idempotency_key` field of the message.
+fn idempotency_key(
+    headers: &HeaderMap,
+    payload: &MessagePayload,
+) -> Result<Option<String>, ValidationErrors> {
+    let key = match headers.get(IDEMPOTENCY_KEY) {
+        Some(value) => Some(
+            value
+                .to_str()
+                .map_err(|_| ValidationErrors::single("Idempotency-Key: must be ASCII"))?
+                .to_string(),
+        ),
+        None => payload.idempotency_key.clone(),
+    };
+
+    if let Some(key) = &key {
+        check_idempotency_key(key)?;
+    }
+
+    Ok(key)
+}
+
+/// Claim an idempotency key for a send before sending it, so that repeats of the send
+/// that arrive while it is being sent don't send it again. Returns `None` once claimed,
+/// or otherwise the ID of the message already sent with the key, waiting for it to be
+/// stored if the first send is still in progress.
+async fn claim_idempotency_key(
+    server_state: &ServerState,
+    channel_id: &str,
+    key: &str,
+) -> WebResult<Option<String>> {
+    let db = server_state.db();
+    let pending_timeout = chrono::Duration::seconds(IDEMPOTENCY_PENDING_SECS);
+
+    loop {
+        let now = Utc::now();
+        let claim = IdempotencyRecord {
+            message_id: None,
+            time: now,
+        };
+
+        if db
+            .try_put_idempotency_record(
+                channel_id,
+                key,
+                &claim,
+                now - server_state.idempotency_window,
+            )
+            .await
+            .log_error_internal()?
+        {
+            return Ok(None);
+        }
+
+        let record = db
+            .get_idempotency_record(channel_id, key)
+            .await
+            .log_error_internal()?;
+        match record {
+            Some(IdempotencyRecord {
+                message_id: Some(message_id),
+                ..
+            }) => return Ok(Some(message_id)),
+            // The first send failed before storing its message, so take over its claim.
+            // Storing the message updates the record's time, so it can't be taken over
+            // once it has been sent.
+            Some(record) if now - record.time >= pending_timeout => {
+                if db
+                    .try_put_idempotency_record(channel_id, key, &claim, now - pending_timeout)
+                    .await
+                    .log_error_internal()?
+                {
+                    return Ok(None);
+                }
+            }
+            _ => sleep(Duration::from_millis(IDEMPOTENCY_POLL_MS)).await,
+        }
+    }
+}
+
+/// Record the message sent with an idempotency key. The message has already been sent by
+/// this point, so failures are logged rather than returned.
+async fn remember_idempotency_key(
+    db: &dyn NotifyDatabase,
+    channel_id: &str,
+    key: Option<String>,
+    message_id: &str,
+) {
+    if let Some(key) = key {
+        let record = IdempotencyRecord {
+            message_id: Some(message_id.to_string()),
+            time: Utc::now(),
+        };
+
+        if let Err(error) = db.put_idempotency_record(channel_id, &key, &record).await {
+            tracing::error!(?error, %channel_id, %message_id, "Could not store idempotency key.");
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct HeartbeatQuery {
+    /// Seconds between pings. Required on the first ping; later pings keep the current
+    /// interval unless they give a new one.
+    interval: Option<u32>,
+
+    /// Seconds allowed after a ping is due before it is considered missed.
+    grace: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct HeartbeatInfo {
+    interval_secs: u32,
+    grace_secs: u32,
+
+    /// Time by which the next ping must arrive.
+    deadline: DateTime<Utc>,
+}
+
+/// Record a heartbeat ping, starting to expect them if this is the first. If pings had
+/// been missed, subscribers are told that they have resumed.
+async fn heartbeat(
+    server_state: Extension<ServerState>,
+    Path(channel_id): Path<String>,
+    Query(query): Query<HeartbeatQuery>,
+    token: RequestToken,
+) -> Result<Response<BoxBody>, StatusCode> {
+    let db = server_state.db();
+    let channel = db.get_channel(&channel_id).await.log_error_not_found()?;
+    token
+        .authorize(channel.send_token_hash.as_deref())
+        .log_error_forbidden()?;
+    let previous = db.get_heartbeat(&channel_id).await.log_error_internal()?;
+
+    let interval_secs = query
+        .interval
+        .or_else(|| previous.as_ref().map(|heartbeat| heartbeat.interval_secs));
+    let grace_secs = query
+        .grace
+        .or_else(|| previous.as_ref().map(|heartbeat| heartbeat.grace_secs))
+        .unwrap_or(DEFAULT_HEARTBEAT_GRACE_SECS);
+
+    let interval_range = MIN_HEARTBEAT_INTERVAL_SECS..=MAX_HEARTBEAT_INTERVAL_SECS;
+    let mut errors = Vec::new();
+    if !interval_secs.is_some_and(|secs| interval_range.contains(&secs)) {
+        errors.push(format!(
+            "interval: must be {} to {} seconds",
+            MIN_HEARTBEAT_INTERVAL_SECS, MAX_HEARTBEAT_INTERVAL_SECS
         ));
     }
+    if grace_secs > MAX_HEARTBEAT_INTERVAL_SECS {
+        errors.push(format!(
+            "grace: must be at most {} seconds",
+            MAX_HEARTBEAT_INTERVAL_SECS
+        ));
+    }
+    if !errors.is_empty() {
+        return Ok((StatusCode::BAD_REQUEST, Json(ValidationErrors { errors }))
+            .into_response()
+            .map(box_body));
+    }
+    let interval_secs = interval_secs.unwrap_or_default();
+
+    let now = Utc::now();
+    let heartbeat = Heartbeat {
+        interval_secs,
+        grace_secs,
+        last_ping: now,
+        deadline: now + chrono::Duration::seconds(i64::from(interval_secs + grace_secs)),
+        missed: false,
+    };
+    db.put_heartbeat(&channel_id, &heartbeat)
+        .await
+        .log_error_internal()?;
+
+    if let Some(previous) = previous.filter(|previous| previous.missed) {
+        let body = format!(
+            "Heartbeat received again, after none since {}.",
+            previous.last_ping.format("%Y-%m-%d %H:%M:%S UTC")
+        );
+        let result = send_notice(
+            &server_state,
+            &channel_id,
+            "Heartbeat resumed",
+            &body,
+            Priority::Default,
+        )
+        .await;
+        if let Err(error) = result {
+            tracing::error!(?error, %channel_id, "Could not send heartbeat resumed notice.");
+        }
+    }
 
-    let message_result = join_all(futures.into_iter()).await;
+    Ok(Json(HeartbeatInfo {
+        interval_secs,
+        grace_secs,
+        deadline: heartbeat.deadline,
+    })
+    .into_response()
+    .map(box_body))
+}
 
-    tracing::info!(%channel_id, ?message_result, "Message sent.");
+/// Stop expecting heartbeat pings.
+async fn stop_heartbeat(
+    server_state: Extension<ServerState>,
+    Path(channel_id): Path<String>,
+    token: RequestToken,
+) -> Result<Json<()>, StatusCode> {
+    let db = server_state.db();
+    let channel = db.get_channel(&channel_id).await.log_error_not_found()?;
+    token
+        .authorize(channel.send_token_hash.as_deref())
+        .log_error_forbidden()?;
 
-    // Store message.
-    let messages: Collection<Message> = channels.subcollection(&channel_id, MESSAGES_COLLECTION);
-
-    messages
-        .create(&Message {
-            message: payload.message.to_string(),
-            message_time: Utc::now(),
-            sender_ip: addr.ip().to_string(),
-            result: message_result,
-        })
+    db.delete_heartbeat(&channel_id)
         .await
         .log_error_internal()?;
 
-    Ok("ok".to_string())
+    Ok(Json(()))
+}
+
+/// Refusal to change or delete a channel registered without a send token. Anyone who knows
+/// the ID of such a channel can send to it, but can't be trusted to own it.
+fn send_token_required(channel_id: &str) -> Response<BoxBody> {
+    tracing::info!(%channel_id, "Refused change to channel without a send token.");
+
+    let errors = ValidationErrors::single(
+        "token: this channel has no send token, so it can't be changed or deleted; \
+        register a channel with send_token set to be able to",
+    );
+    (StatusCode::FORBIDDEN, Json(errors))
+        .into_response()
+        .map(box_body)
+}
+
+#[derive(Deserialize)]
+struct DeleteChannelQuery {
+    /// Tell subscribers that the channel has been deleted, before deleting it.
+    #[serde(default)]
+    notify: bool,
+}
+
+/// Delete a channel and everything stored for it.
+async fn delete_channel(
+    server_state: Extension<ServerState>,
+    Path(channel_id): Path<String>,
+    Query(query): Query<DeleteChannelQuery>,
+    token: RequestToken,
+) -> Result<Response<BoxBody>, StatusCode> {
+    let db = server_state.db();
+    let channel = db.get_channel(&channel_id).await.log_error_not_found()?;
+    if channel.send_token_hash.is_none() {
+        return Ok(send_token_required(&channel_id));
+    }
+    token
+        .authorize(channel.send_token_hash.as_deref())
+        .log_error_forbidden()?;
+
+    if query.notify {
+        let result = send_notice(
+            &server_state,
+            &channel_id,
+            "Channel deleted",
+            "This channel has been deleted, and will not send any more notifications.",
+            Priority::Default,
+        )
+        .await;
+        if let Err(error) = result {
+            tracing::error!(?error, %channel_id, "Could not send channel deleted notice.");
+        }
+    }
+
+    db.delete_channel(&channel_id).await.log_error_internal()?;
+
+    tracing::info!(%channel_id, "Channel deleted.");
+
+    Ok(Json(()).into_response().map(box_body))
 }
 
 #[derive(Deserialize)]
@@ -231,29 +966,110 @@ async fn subscribe(
     subscription: Json<SubscriptionRequest>,
     server_state: Extension<ServerState>,
     Path(channel_id): Path<String>,
+    token: RequestToken,
 ) -> Result<Json<()>, StatusCode> {
-    let db = server_state.db().await.log_error_internal()?;
+    let db = server_state.db();
+    let channel = db.get_channel(&channel_id).await.log_error_not_found()?;
+    token
+        .authorize(channel.read_token_hash.as_deref())
+        .log_error_forbidden()?;
 
-    let channels = db.channels();
-    channels.get(&*channel_id).await.log_error_not_found()?;
+    let subscription_id = subscription.id.clone();
+    let subscription = Subscription {
+        endpoint: subscription.0.subscription.endpoint,
+        auth: subscription.0.subscription.keys.auth,
+        p256dh: subscription.0.subscription.keys.p256dh,
+        deactivated: None,
+    };
 
-    let subscriptions: Collection<Subscription> =
-        channels.subcollection(&channel_id, SUBSCRIPTIONS_COLLECTION);
+    db.put_subscription(&channel_id, &subscription_id, &subscription)
+        .await
+        .log_error_internal()?;
 
-    let subscription_id = subscription.id.clone();
+    // A browser whose push subscription changed may have subscribed again under a new ID,
+    // so remove any other subscriptions with the same endpoint, to avoid sending it duplicates.
+    let subscriptions = db
+        .list_subscriptions(&channel_id)
+        .await
+        .log_error_internal()?;
+    for duplicate in subscriptions.into_iter().filter(|other| {
+        other.id != subscription_id && other.value.endpoint == subscription.endpoint
+    }) {
+        db.delete_subscription(&channel_id, &duplicate.id)
+            .await
+            .log_error_internal()?;
+        tracing::info!(%channel_id, subscription_id = %duplicate.id, "Duplicate subscription removed.");
+    }
 
-    subscriptions
-        .try_create(
-            &Subscription {
-                endpoint: subscription.0.subscription.endpoint,
-                auth: subscription.0.subscription.keys.auth,
-                p256dh: subscription.0.subscription.keys.p256dh,
-            },
-            &*subscription_id,
-        )
+    Ok(Json(()))
+}
+
+/// Remove a subscription, so that its browser is no longer sent the channel's messages.
+async fn unsubscribe(
+    server_state: Extension<ServerState>,
+    Path((channel_id, subscription_id)): Path<(String, String)>,
+    token: RequestToken,
+) -> Result<Json<()>, StatusCode> {
+    let db = server_state.db();
+    let channel = db.get_channel(&channel_id).await.log_error_not_found()?;
+    token
+        .authorize(channel.read_token_hash.as_deref())
+        .log_error_forbidden()?;
+
+    let deleted = db
+        .delete_subscription(&channel_id, &subscription_id)
+        .await
+        .log_error_internal()?;
+    if !deleted {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    tracing::info!(%channel_id, %subscription_id, "Subscription removed.");
+
+    Ok(Json(()))
+}
+
+#[derive(Deserialize)]
+struct UnsubscribeRequest {
+    endpoint: String,
+}
+
+/// Remove the subscriptions with a given push endpoint, for clients that don't know the
+/// ID they subscribed with.
+async fn unsubscribe_endpoint(
+    request: Json<UnsubscribeRequest>,
+    server_state: Extension<ServerState>,
+    Path(channel_id): Path<String>,
+    token: RequestToken,
+) -> Result<Json<()>, StatusCode> {
+    let db = server_state.db();
+    let channel = db.get_channel(&channel_id).await.log_error_not_found()?;
+    token
+        .authorize(channel.read_token_hash.as_deref())
+        .log_error_forbidden()?;
+
+    let subscriptions = db
+        .list_subscriptions(&channel_id)
         .await
         .log_error_internal()?;
 
+    let mut deleted = false;
+    for subscription in subscriptions {
+        if subscription.value.endpoint != request.endpoint {
+            continue;
+        }
+
+        deleted |= db
+            .delete_subscription(&channel_id, &subscription.id)
+            .await
+            .log_error_internal()?;
+        tracing::info!(%channel_id, subscription_id = %subscription.id, "Subscription removed.");
+    }
+
+    if !deleted {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
     Ok(Json(()))
 }
 
@@ -306,7 +1122,7 @@ pub async fn redirect(
     Path(channel_id): Path<String>,
 ) -> Response<Body> {
     if channel_id.len() > 6 && channel_id.chars().all(|c| char::is_ascii_alphanumeric(&c)) {
-        let new_location = server_state.channel_page_url(&*channel_id);
+        let new_location = server_state.channel_page_url(&channel_id);
         Response::builder()
             .status(302)
             .header(
@@ -341,24 +1157,40 @@ pub async fn moved_service_worker(server_state: Extension<ServerState>) -> Respo
         .unwrap()
 }
 
-async fn active_routes() -> Router {
-    let server_state = ServerState::new().await;
-
+fn active_routes(server_state: ServerState) -> Router {
     Router::new()
         .route("/service-worker.js", get(moved_service_worker))
         .route("/:channel_id/qr.svg", get(render_qr_code))
         .route("/:channel_id/json", get(info))
-        .route("/:channel_id/subscribe", post(subscribe))
+        .route(
+            "/:channel_id/subscribe",
+            post(subscribe).delete(unsubscribe_endpoint),
+        )
+        .route(
+            "/:channel_id/subscribe/:subscription_id",
+            delete(unsubscribe),
+        )
+        .route("/:channel_id/messages/:message_id/cancel", post(cancel))
+        .route(
+            "/:channel_id/heartbeat",
+            get(heartbeat).post(heartbeat).delete(stop_heartbeat),
+        )
         .route("/api/register_channel", post(register_channel))
         .route("/register_channel", post(register_channel)) // Used by py client.
-        .route("/:channel_id", get(redirect).post(send))
+        .route(
+            "/:channel_id",
+            get(redirect)
+                .post(send)
+                .patch(update_channel)
+                .delete(delete_channel),
+        )
         .layer(AddExtensionLayer::new(server_state))
         .layer(layer_fn(|inner| {
             RateLimiterMiddleware::new(inner, Quota::per_minute(nonzero!(MAX_REQUESTS_PER_MINUTE)))
         }))
 }
 
-pub async fn serve(port: Option<u16>) -> anyhow::Result<()> {
+pub async fn serve(port: Option<u16>, database: Arc<dyn NotifyDatabase>) -> anyhow::Result<()> {
     let port: u16 = if let Some(port) = port {
         port
     } else if let Ok(port) = std::env::var("PORT") {
@@ -367,9 +1199,12 @@ pub async fn serve(port: Option<u16>) -> anyhow::Result<()> {
         8080
     };
 
+    let server_state = ServerState::from_env(database).with_delivery_queue(DELIVERY_WORKERS);
+    tokio::spawn(run_scheduler(server_state.clone()));
+
     let app = Router::new()
         .route("/undefined", get(undefined).post(undefined))
-        .merge(active_routes().await)
+        .merge(active_routes(server_state))
         .fallback(static_routes());
 
     let addr = SocketAddr::from(([0, 0, 0, 0], port));
@@ -380,3 +1215,982 @@ pub async fn serve(port: Option<u16>) -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::memory::MemoryDatabase;
+    use crate::delivery::test::{
+        channel_with_subscription, test_server_state, test_subscription, FakePushService,
+    };
+    use axum::http::Request;
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    fn test_router() -> Router {
+        test_router_with_database(Arc::new(MemoryDatabase::new()))
+    }
+
+    fn test_router_with_database(database: Arc<MemoryDatabase>) -> Router {
+        let server_state = ServerState::new(
+            database,
+            "http://notify.test".to_string(),
+            "test-pubkey".to_string(),
+            Vec::new(),
+        );
+
+        active_routes(server_state)
+    }
+
+    fn request(method: &str, uri: &str, body: Body) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("user-agent", "test-agent")
+            .header("content-type", "application/json")
+            .extension(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 1234))))
+            .body(body)
+            .unwrap()
+    }
+
+    /// A request with a plain text body, as sent by most notify.run clients.
+    fn text_request(method: &str, uri: &str, body: &str) -> Request<Body> {
+        let mut request = request(method, uri, Body::from(body.to_string()));
+        request
+            .headers_mut()
+            .insert("content-type", "text/plain".parse().unwrap());
+        request
+    }
+
+    async fn call(router: &Router, request: Request<Body>) -> (StatusCode, Bytes) {
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        (status, body)
+    }
+
+    async fn register(router: &Router) -> String {
+        let (status, body) = call(
+            router,
+            request("POST", "/api/register_channel", Body::empty()),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, status);
+
+        let info: Value = serde_json::from_slice(&body).unwrap();
+        info["channelId"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_register_channel() {
+        let router = test_router();
+        let channel_id = register(&router).await;
+
+        let (status, body) = call(
+            &router,
+            request("GET", &format!("/{}/json", channel_id), Body::empty()),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, status);
+
+        let info: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!("test-pubkey", info["pubKey"]);
+        assert_eq!(
+            format!("http://notify.test/{}", channel_id),
+            info["endpoint"]
+        );
+        assert_eq!(0, info["messages"].as_array().unwrap().len());
+        assert!(info.get("send_token").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_register_channel_with_defaults() {
+        let database = Arc::new(MemoryDatabase::new());
+        let router = test_router_with_database(database.clone());
+
+        let settings = serde_json::json!({"default_silent": true});
+        let (status, body) = call(
+            &router,
+            request(
+                "POST",
+                "/api/register_channel",
+                Body::from(settings.to_string()),
+            ),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, status);
+
+        let info: Value = serde_json::from_slice(&body).unwrap();
+        let channel = database
+            .get_channel(info["channelId"].as_str().unwrap())
+            .await
+            .unwrap();
+        assert!(channel.default_silent);
+        assert!(!channel.default_vibrate);
+
+        let (status, _) = call(
+            &router,
+            request("POST", "/api/register_channel", Body::from("{not json")),
+        )
+        .await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+    }
+
+    #[tokio::test]
+    async fn test_channel_metadata() {
+        let router = test_router();
+
+        let settings = serde_json::json!({"default_icon": "ftp://example.com/icon.png"});
+        let (status, _) = call(
+            &router,
+            request(
+                "POST",
+                "/api/register_channel",
+                Body::from(settings.to_string()),
+            ),
+        )
+        .await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+
+        let settings = serde_json::json!({
+            "name": "Backups",
+            "default_action": "https://example.com/backups",
+            "send_token": true,
+        });
+        let (status, body) = call(
+            &router,
+            request(
+                "POST",
+                "/api/register_channel",
+                Body::from(settings.to_string()),
+            ),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, status);
+
+        let info: Value = serde_json::from_slice(&body).unwrap();
+        let channel_id = info["channelId"].as_str().unwrap();
+        assert_eq!("Backups", info["name"]);
+
+        let update = serde_json::json!({"default_action": "https://phishing.example.com/"});
+        let (status, _) = call(
+            &router,
+            request(
+                "PATCH",
+                &format!("/{}", channel_id),
+                Body::from(update.to_string()),
+            ),
+        )
+        .await;
+        assert_eq!(StatusCode::FORBIDDEN, status);
+
+        // Empty fields clear the setting, and missing ones leave it unchanged.
+        let uri = format!(
+            "/{}?token={}",
+            channel_id,
+            info["send_token"].as_str().unwrap()
+        );
+        let update = serde_json::json!({"name": "", "description": "Nightly backups"});
+        let (status, body) = call(
+            &router,
+            request("PATCH", &uri, Body::from(update.to_string())),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, status);
+
+        let metadata: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            serde_json::json!({
+                "description": "Nightly backups",
+                "default_action": "https://example.com/backups",
+            }),
+            metadata
+        );
+
+        let update = serde_json::json!({"default_action": "backups"});
+        let (status, _) = call(
+            &router,
+            request("PATCH", &uri, Body::from(update.to_string())),
+        )
+        .await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+
+        let (_, body) = call(
+            &router,
+            request("GET", &format!("/{}/json", channel_id), Body::empty()),
+        )
+        .await;
+        let info: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!("Nightly backups", info["description"]);
+        assert_eq!("https://example.com/backups", info["default_action"]);
+        assert!(info.get("name").is_none());
+
+        // Channels without a send token can't be changed at all.
+        let channel_id = register(&router).await;
+        let (status, _) = call(
+            &router,
+            request(
+                "PATCH",
+                &format!("/{}", channel_id),
+                Body::from(update.to_string()),
+            ),
+        )
+        .await;
+        assert_eq!(StatusCode::FORBIDDEN, status);
+    }
+
+    #[tokio::test]
+    async fn test_channel_tokens() {
+        let router = test_router();
+
+        let settings = serde_json::json!({"send_token": true, "read_token": true});
+        let (status, body) = call(
+            &router,
+            request(
+                "POST",
+                "/api/register_channel",
+                Body::from(settings.to_string()),
+            ),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, status);
+
+        let info: Value = serde_json::from_slice(&body).unwrap();
+        let channel_id = info["channelId"].as_str().unwrap();
+        let send_token = info["send_token"].as_str().unwrap();
+        let read_token = info["read_token"].as_str().unwrap();
+
+        let uri = format!("/{}", channel_id);
+        let (status, _) = call(&router, text_request("POST", &uri, "hello")).await;
+        assert_eq!(StatusCode::FORBIDDEN, status);
+
+        let mut send = text_request("POST", &uri, "hello");
+        send.headers_mut().insert(
+            "authorization",
+            format!("Bearer {}", read_token).parse().unwrap(),
+        );
+        let (status, _) = call(&router, send).await;
+        assert_eq!(StatusCode::FORBIDDEN, status);
+
+        let mut send = text_request("POST", &uri, "hello");
+        send.headers_mut().insert(
+            "authorization",
+            format!("Bearer {}", send_token).parse().unwrap(),
+        );
+        let (status, _) = call(&router, send).await;
+        assert_eq!(StatusCode::OK, status);
+
+        let uri = format!("/{}/json", channel_id);
+        let (status, _) = call(&router, request("GET", &uri, Body::empty())).await;
+        assert_eq!(StatusCode::FORBIDDEN, status);
+
+        let uri = format!("/{}/json?token={}", channel_id, read_token);
+        let (status, body) = call(&router, request("GET", &uri, Body::empty())).await;
+        assert_eq!(StatusCode::OK, status);
+
+        let info: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(1, info["messages"].as_array().unwrap().len());
+        assert!(info.get("send_token").is_none());
+        assert!(info.get("read_token").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_delete_channel() {
+        let database = Arc::new(MemoryDatabase::new());
+        let router = test_router_with_database(database.clone());
+
+        let settings = serde_json::json!({"send_token": true});
+        let (_, body) = call(
+            &router,
+            request(
+                "POST",
+                "/api/register_channel",
+                Body::from(settings.to_string()),
+            ),
+        )
+        .await;
+        let info: Value = serde_json::from_slice(&body).unwrap();
+        let channel_id = info["channelId"].as_str().unwrap();
+        let send_token = info["send_token"].as_str().unwrap();
+
+        database
+            .put_subscription(
+                channel_id,
+                "sub1",
+                &Subscription {
+                    endpoint: "https://push.example.com/abc".to_string(),
+                    auth: "auth".to_string(),
+                    p256dh: "p256dh".to_string(),
+                    deactivated: None,
+                },
+            )
+            .await
+            .unwrap();
+
+        let uri = format!("/{}", channel_id);
+        let (status, _) = call(&router, request("DELETE", &uri, Body::empty())).await;
+        assert_eq!(StatusCode::FORBIDDEN, status);
+        assert!(database.get_channel(channel_id).await.is_ok());
+
+        let uri = format!("/{}?notify=true&token={}", channel_id, send_token);
+        let (status, _) = call(&router, request("DELETE", &uri, Body::empty())).await;
+        assert_eq!(StatusCode::OK, status);
+        assert!(database.get_channel(channel_id).await.is_err());
+
+        let (status, _) = call(
+            &router,
+            request("GET", &format!("/{}/json", channel_id), Body::empty()),
+        )
+        .await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+    }
+
+    #[tokio::test]
+    async fn test_delete_unprotected_channel() {
+        let database = Arc::new(MemoryDatabase::new());
+        let router = test_router_with_database(database.clone());
+        let channel_id = register(&router).await;
+
+        let (status, body) = call(
+            &router,
+            request("DELETE", &format!("/{}", channel_id), Body::empty()),
+        )
+        .await;
+        assert_eq!(StatusCode::FORBIDDEN, status);
+        let errors: Value = serde_json::from_slice(&body).unwrap();
+        assert!(errors["errors"][0]
+            .as_str()
+            .unwrap()
+            .contains("no send token"));
+        assert!(database.get_channel(&channel_id).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_channel() {
+        let router = test_router();
+
+        let (status, _) = call(
+            &router,
+            request("GET", "/nosuchchannel/json", Body::empty()),
+        )
+        .await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+
+        let (status, _) = call(
+            &router,
+            request("POST", "/nosuchchannel", Body::from("hello")),
+        )
+        .await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_and_send() {
+        let router = test_router();
+        let channel_id = register(&router).await;
+
+        let subscription = serde_json::json!({
+            "id": "sub1",
+            "subscription": {
+                "endpoint": "https://push.example.com/abc",
+                "keys": {"auth": "auth", "p256dh": "p256dh"},
+            },
+        });
+        let (status, _) = call(
+            &router,
+            request(
+                "POST",
+                &format!("/{}/subscribe", channel_id),
+                Body::from(subscription.to_string()),
+            ),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, status);
+
+        let (status, body) = call(
+            &router,
+            text_request("POST", &format!("/{}", channel_id), "hello"),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(&b"ok"[..], &body[..]);
+
+        let (_, body) = call(
+            &router,
+            request("GET", &format!("/{}/json", channel_id), Body::empty()),
+        )
+        .await;
+        let info: Value = serde_json::from_slice(&body).unwrap();
+        let messages = info["messages"].as_array().unwrap();
+        assert_eq!(1, messages.len());
+        assert_eq!("hello", messages[0]["message"]);
+
+        // The test server has no valid VAPID key, so delivery fails, but the attempt is recorded.
+        let result = messages[0]["result"].as_array().unwrap();
+        assert_eq!(1, result.len());
+        assert_eq!("push.example.com", result[0]["endpoint_domain"]);
+    }
+
+    #[tokio::test]
+    async fn test_send_reaches_all_subscriptions() {
+        let database = Arc::new(MemoryDatabase::new());
+        let router = test_router_with_database(database.clone());
+        let channel_id = register(&router).await;
+
+        for i in 0..25 {
+            database
+                .put_subscription(
+                    &channel_id,
+                    &format!("sub{}", i),
+                    &Subscription {
+                        endpoint: format!("https://push{}.example.com/abc", i),
+                        auth: "auth".to_string(),
+                        p256dh: "p256dh".to_string(),
+                        deactivated: None,
+                    },
+                )
+                .await
+                .unwrap();
+        }
+
+        let (status, _) = call(
+            &router,
+            text_request("POST", &format!("/{}", channel_id), "hello"),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, status);
+
+        let messages = database.list_messages(&channel_id, 0, 1).await.unwrap();
+        assert_eq!(25, messages[0].result.len());
+        assert_eq!("push24.example.com", messages[0].result[24].endpoint_domain);
+    }
+
+    #[tokio::test]
+    async fn test_resubscribe() {
+        let database = Arc::new(MemoryDatabase::new());
+        let router = test_router_with_database(database.clone());
+        let channel_id = register(&router).await;
+        let uri = format!("/{}/subscribe", channel_id);
+
+        for (id, endpoint) in [
+            ("sub1", "https://push.example.com/abc"),
+            ("sub1", "https://push.example.com/def"),
+            ("sub2", "https://push.example.com/def"),
+        ] {
+            let subscription = serde_json::json!({
+                "id": id,
+                "subscription": {
+                    "endpoint": endpoint,
+                    "keys": {"auth": "auth", "p256dh": "p256dh"},
+                },
+            });
+            let (status, _) = call(
+                &router,
+                request("POST", &uri, Body::from(subscription.to_string())),
+            )
+            .await;
+            assert_eq!(StatusCode::OK, status);
+
+            // Each subscription replaces the one before, either by ID or by endpoint.
+            let subscriptions = database.list_subscriptions(&channel_id).await.unwrap();
+            assert_eq!(1, subscriptions.len());
+            assert_eq!(id, subscriptions[0].id);
+            assert_eq!(endpoint, subscriptions[0].value.endpoint);
+        }
+
+        // Duplicates stored some other way are still only sent to once.
+        database
+            .put_subscription(
+                &channel_id,
+                "sub3",
+                &Subscription {
+                    endpoint: "https://push.example.com/def".to_string(),
+                    auth: "auth".to_string(),
+                    p256dh: "p256dh".to_string(),
+                    deactivated: None,
+                },
+            )
+            .await
+            .unwrap();
+
+        let (status, _) = call(
+            &router,
+            text_request("POST", &format!("/{}", channel_id), "hello"),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, status);
+
+        let messages = database.list_messages(&channel_id, 0, 1).await.unwrap();
+        assert_eq!(1, messages[0].result.len());
+    }
+
+    #[tokio::test]
+    async fn test_subscription_rotation() {
+        let (old_service, old_endpoint) = FakePushService::start(vec![]);
+        let (new_service, new_endpoint) = FakePushService::start(vec![]);
+        let server_state = test_server_state();
+        let db = server_state.db();
+        let channel_id = db
+            .create_channel(&Channel {
+                read_token_hash: Some(hash_token("secret")),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        db.put_subscription(&channel_id, "old-id", &test_subscription(&old_endpoint))
+            .await
+            .unwrap();
+        let router = active_routes(server_state.clone());
+
+        // When the push service replaces the browser's subscription, the service worker sends
+        // the new one under the ID of the old one.
+        let new_subscription = test_subscription(&new_endpoint);
+        let subscription = serde_json::json!({
+            "id": "old-id",
+            "subscription": {
+                "endpoint": new_endpoint,
+                "keys": {"auth": new_subscription.auth, "p256dh": new_subscription.p256dh},
+            },
+        });
+        let (status, _) = call(
+            &router,
+            request(
+                "POST",
+                &format!("/{}/subscribe?token=secret", channel_id),
+                Body::from(subscription.to_string()),
+            ),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, status);
+
+        let (status, _) = call(
+            &router,
+            text_request("POST", &format!("/{}", channel_id), "hello"),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(0, old_service.requests.lock().unwrap().len());
+        assert_eq!(1, new_service.requests.lock().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_unsubscribe() {
+        let database = Arc::new(MemoryDatabase::new());
+        let router = test_router_with_database(database.clone());
+        let channel_id = register(&router).await;
+
+        for i in 0..3 {
+            database
+                .put_subscription(
+                    &channel_id,
+                    &format!("sub{}", i),
+                    &Subscription {
+                        endpoint: format!("https://push{}.example.com/abc", i % 2),
+                        auth: "auth".to_string(),
+                        p256dh: "p256dh".to_string(),
+                        deactivated: None,
+                    },
+                )
+                .await
+                .unwrap();
+        }
+
+        let uri = format!("/{}/subscribe/sub1", channel_id);
+        let (status, _) = call(&router, request("DELETE", &uri, Body::empty())).await;
+        assert_eq!(StatusCode::OK, status);
+        let (status, _) = call(&router, request("DELETE", &uri, Body::empty())).await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+
+        // Removes both subscriptions with the endpoint.
+        let uri = format!("/{}/subscribe", channel_id);
+        let endpoint = serde_json::json!({"endpoint": "https://push0.example.com/abc"});
+        let (status, _) = call(
+            &router,
+            request("DELETE", &uri, Body::from(endpoint.to_string())),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, status);
+        let (status, _) = call(
+            &router,
+            request("DELETE", &uri, Body::from(endpoint.to_string())),
+        )
+        .await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+
+        assert!(database
+            .list_subscriptions(&channel_id)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_send_async() {
+        let (service, endpoint) = FakePushService::start(vec![]);
+        let server_state = test_server_state().with_delivery_queue(1);
+        let channel_id = channel_with_subscription(&server_state, &endpoint).await;
+        let router = active_routes(server_state.clone());
+
+        let (status, body) = call(
+            &router,
+            text_request("POST", &format!("/{}?async=true", channel_id), "hello"),
+        )
+        .await;
+        assert_eq!(StatusCode::ACCEPTED, status);
+        let accepted: Value = serde_json::from_slice(&body).unwrap();
+        assert!(accepted["message_id"].is_string());
+
+        // The message is stored as queued before it is delivered, and its status and result
+        // are filled in afterwards.
+        let db = server_state.db();
+        let mut message = None;
+        for _ in 0..100 {
+            let messages = db.list_messages(&channel_id, 0, 1).await.unwrap();
+            assert_eq!("hello", messages[0].message);
+            if messages[0].status == MessageStatus::Queued {
+                assert!(messages[0].result.is_empty());
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                continue;
+            }
+            message = messages.into_iter().next();
+            break;
+        }
+        let message = message.unwrap();
+        assert_eq!(MessageStatus::Sent, message.status);
+        assert_eq!("201", message.result[0].result_status);
+        assert_eq!(1, service.requests.lock().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_send_json_response() {
+        let (_, endpoint) = FakePushService::start(vec![(410, None)]);
+        let server_state = test_server_state();
+        let channel_id = channel_with_subscription(&server_state, &endpoint).await;
+        let router = active_routes(server_state.clone());
+
+        let mut send_request = text_request("POST", &format!("/{}", channel_id), "hello");
+        send_request
+            .headers_mut()
+            .insert("accept", "application/json".parse().unwrap());
+        let (status, body) = call(&router, send_request).await;
+        assert_eq!(StatusCode::OK, status);
+
+        let response: Value = serde_json::from_slice(&body).unwrap();
+        assert!(response["message_id"].is_string());
+        assert_eq!(1, response["subscriptions"]);
+        assert_eq!(false, response["results"][0]["delivered"]);
+        assert_eq!(
+            endpoint.split('/').nth(2).unwrap(),
+            response["results"][0]["endpoint_domain"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_json_body() {
+        let database = Arc::new(MemoryDatabase::new());
+        let router = test_router_with_database(database.clone());
+        let channel_id = register(&router).await;
+
+        let message = serde_json::json!({"message": "deploy finished", "ttl": 3600});
+        let (status, _) = call(
+            &router,
+            request(
+                "POST",
+                &format!("/{}", channel_id),
+                Body::from(message.to_string()),
+            ),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, status);
+
+        let messages = database.list_messages(&channel_id, 0, 1).await.unwrap();
+        assert_eq!("deploy finished", messages[0].message);
+
+        let message = serde_json::json!({"message": "", "urgency": "whenever"});
+        let (status, body) = call(
+            &router,
+            request(
+                "POST",
+                &format!("/{}", channel_id),
+                Body::from(message.to_string()),
+            ),
+        )
+        .await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+        let response: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(1, response["errors"].as_array().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_send_oversize_message() {
+        let database = Arc::new(MemoryDatabase::new());
+        let router = test_router_with_database(database.clone());
+        let channel_id = register(&router).await;
+        let long_text = "x".repeat(5000);
+
+        let (status, body) = call(
+            &router,
+            text_request("POST", &format!("/{}", channel_id), &long_text),
+        )
+        .await;
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, status);
+        let response: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(1, response["errors"].as_array().unwrap().len());
+        assert!(database
+            .list_messages(&channel_id, 0, 1)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let message = serde_json::json!({"message": long_text, "truncate": true});
+        let (status, _) = call(
+            &router,
+            request(
+                "POST",
+                &format!("/{}", channel_id),
+                Body::from(message.to_string()),
+            ),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, status);
+
+        // The stored message keeps the full text.
+        let messages = database.list_messages(&channel_id, 0, 1).await.unwrap();
+        assert_eq!(long_text, messages[0].message);
+    }
+
+    #[tokio::test]
+    async fn test_send_idempotency_key() {
+        let (service, endpoint) = FakePushService::start(vec![]);
+        let server_state = test_server_state();
+        let channel_id = channel_with_subscription(&server_state, &endpoint).await;
+        let router = active_routes(server_state.clone());
+
+        let send_request = |key: &str| {
+            let mut send_request = text_request("POST", &format!("/{}", channel_id), "hello");
+            let headers = send_request.headers_mut();
+            headers.insert("accept", "application/json".parse().unwrap());
+            headers.insert(IDEMPOTENCY_KEY, key.parse().unwrap());
+            send_request
+        };
+
+        let first = router.clone().oneshot(send_request("key1")).await.unwrap();
+        assert_eq!(StatusCode::OK, first.status());
+        assert!(first.headers().get(IDEMPOTENT_REPLAYED).is_none());
+        let first: Value =
+            serde_json::from_slice(&hyper::body::to_bytes(first.into_body()).await.unwrap())
+                .unwrap();
+
+        // A retry returns the original result, without pushing the message again.
+        let second = router.clone().oneshot(send_request("key1")).await.unwrap();
+        assert_eq!(StatusCode::OK, second.status());
+        assert_eq!("true", second.headers()[IDEMPOTENT_REPLAYED]);
+        let second: Value =
+            serde_json::from_slice(&hyper::body::to_bytes(second.into_body()).await.unwrap())
+                .unwrap();
+        assert_eq!(first, second);
+        assert_eq!(1, service.requests.lock().unwrap().len());
+
+        let (status, _) = call(&router, send_request("key2")).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(2, service.requests.lock().unwrap().len());
+
+        let messages = server_state
+            .db()
+            .list_messages(&channel_id, 0, 10)
+            .await
+            .unwrap();
+        assert_eq!(2, messages.len());
+    }
+
+    #[tokio::test]
+    async fn test_send_idempotency_key_concurrent() {
+        let (service, endpoint) =
+            FakePushService::start_with_delay(vec![], std::time::Duration::from_millis(500));
+        let server_state = test_server_state();
+        let channel_id = channel_with_subscription(&server_state, &endpoint).await;
+        let router = active_routes(server_state.clone());
+
+        let send_request = || {
+            let mut send_request = text_request("POST", &format!("/{}", channel_id), "hello");
+            let headers = send_request.headers_mut();
+            headers.insert("accept", "application/json".parse().unwrap());
+            headers.insert(IDEMPOTENCY_KEY, "key1".parse().unwrap());
+            send_request
+        };
+
+        // The repeat arrives while the first send is still waiting on the push service.
+        let ((first_status, first), (second_status, second)) =
+            tokio::join!(call(&router, send_request()), async {
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                call(&router, send_request()).await
+            });
+        assert_eq!(StatusCode::OK, first_status);
+        assert_eq!(StatusCode::OK, second_status);
+        assert_eq!(first, second);
+        assert_eq!(1, service.requests.lock().unwrap().len());
+
+        let messages = server_state
+            .db()
+            .list_messages(&channel_id, 0, 10)
+            .await
+            .unwrap();
+        assert_eq!(1, messages.len());
+    }
+
+    #[tokio::test]
+    async fn test_schedule_and_cancel() {
+        let database = Arc::new(MemoryDatabase::new());
+        let router = test_router_with_database(database.clone());
+        let channel_id = register(&router).await;
+
+        let (status, body) = call(
+            &router,
+            text_request(
+                "POST",
+                &format!("/{}", channel_id),
+                "message=check+the+backup&delay=2h",
+            ),
+        )
+        .await;
+        assert_eq!(StatusCode::ACCEPTED, status);
+        let accepted: Value = serde_json::from_slice(&body).unwrap();
+        let message_id = accepted["message_id"].as_str().unwrap();
+        assert!(accepted["deliver_at"].is_string());
+
+        let message = database.get_message(&channel_id, message_id).await.unwrap();
+        assert_eq!(MessageStatus::Pending, message.status);
+        assert!(message.result.is_empty());
+
+        let cancel_uri = format!("/{}/messages/{}/cancel", channel_id, message_id);
+        let (status, _) = call(&router, request("POST", &cancel_uri, Body::empty())).await;
+        assert_eq!(StatusCode::OK, status);
+
+        let message = database.get_message(&channel_id, message_id).await.unwrap();
+        assert_eq!(MessageStatus::Cancelled, message.status);
+        assert!(database
+            .list_due_scheduled_messages(Utc::now() + chrono::Duration::days(1))
+            .await
+            .unwrap()
+            .is_empty());
+
+        let (status, _) = call(&router, request("POST", &cancel_uri, Body::empty())).await;
+        assert_eq!(StatusCode::CONFLICT, status);
+
+        let unknown_uri = format!("/{}/messages/nosuchmessage/cancel", channel_id);
+        let (status, _) = call(&router, request("POST", &unknown_uri, Body::empty())).await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat() {
+        let database = Arc::new(MemoryDatabase::new());
+        let router = test_router_with_database(database.clone());
+        let channel_id = register(&router).await;
+        let heartbeat_uri = format!("/{}/heartbeat", channel_id);
+
+        // The first ping must set an interval.
+        let (status, _) = call(&router, request("GET", &heartbeat_uri, Body::empty())).await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+
+        let (status, body) = call(
+            &router,
+            request(
+                "POST",
+                &format!("{}?interval=3600", heartbeat_uri),
+                Body::empty(),
+            ),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, status);
+        let info: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(3600, info["interval_secs"]);
+        assert_eq!(DEFAULT_HEARTBEAT_GRACE_SECS, info["grace_secs"]);
+
+        let heartbeat = database.get_heartbeat(&channel_id).await.unwrap().unwrap();
+        assert!(database
+            .mark_heartbeat_missed(&channel_id, heartbeat.deadline)
+            .await
+            .unwrap());
+
+        // A ping after a missed one keeps the interval, and says that pings have resumed.
+        let (status, _) = call(&router, request("GET", &heartbeat_uri, Body::empty())).await;
+        assert_eq!(StatusCode::OK, status);
+        let heartbeat = database.get_heartbeat(&channel_id).await.unwrap().unwrap();
+        assert_eq!(3600, heartbeat.interval_secs);
+        assert!(!heartbeat.missed);
+        let messages = database.list_messages(&channel_id, 0, 10).await.unwrap();
+        assert_eq!(Some("Heartbeat resumed"), messages[0].title.as_deref());
+
+        let (status, _) = call(&router, request("DELETE", &heartbeat_uri, Body::empty())).await;
+        assert_eq!(StatusCode::OK, status);
+        assert!(database.get_heartbeat(&channel_id).await.unwrap().is_none());
+    }
+
+    #[test]
+    fn test_accepts_json() {
+        let mut headers = HeaderMap::new();
+        assert!(!accepts_json(&headers));
+
+        headers.insert(
+            "accept",
+            "text/html, application/json;q=0.9".parse().unwrap(),
+        );
+        assert!(accepts_json(&headers));
+    }
+
+    #[test]
+    fn test_prefers_async() {
+        let mut headers = HeaderMap::new();
+        assert!(!prefers_async(&headers));
+
+        headers.insert("prefer", "return=minimal, respond-async".parse().unwrap());
+        assert!(prefers_async(&headers));
+    }
+
+    #[tokio::test]
+    async fn test_info_pagination() {
+        let database = Arc::new(MemoryDatabase::new());
+        let router = test_router_with_database(database.clone());
+        let channel_id = register(&router).await;
+        let start = Utc::now();
+
+        for i in 0..15 {
+            database
+                .create_message(
+                    &channel_id,
+                    &Message {
+                        message: format!("message {}", i),
+                        sender_ip: "127.0.0.1".to_string(),
+                        message_time: start + chrono::Duration::seconds(i),
+                        ..Default::default()
+                    },
+                )
+                .await
+                .unwrap();
+        }
+
+        let (_, body) = call(
+            &router,
+            request("GET", &format!("/{}/json", channel_id), Body::empty()),
+        )
+        .await;
+        let info: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(10, info["messages"].as_array().unwrap().len());
+
+        let (_, body) = call(
+            &router,
+            request(
+                "GET",
+                &format!("/{}/json?offset=10&limit=50", channel_id),
+                Body::empty(),
+            ),
+        )
+        .await;
+        let info: Value = serde_json::from_slice(&body).unwrap();
+        let messages = info["messages"].as_array().unwrap();
+        assert_eq!(5, messages.len());
+        assert_eq!("message 4", messages[0]["message"]);
+    }
+}
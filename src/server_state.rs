@@ -1,24 +1,53 @@
-use std::convert::Infallible;
+use std::sync::Arc;
 
 use base64::URL_SAFE;
-use deadpool::managed::{Object, PoolError};
+use chrono::Duration;
 
-use crate::database::NotifyDatabaseManager;
+use crate::database::NotifyDatabase;
+use crate::delivery::{DeliveryQueue, RetryPolicy};
+use crate::model::Channel;
+use crate::vapid::{push_client, PushClient};
 
 #[derive(Clone)]
 pub struct ServerState {
-    pool: deadpool::managed::Pool<NotifyDatabaseManager>,
+    database: Arc<dyn NotifyDatabase>,
     pub server_base: String,
     pub vapid_pubkey: String,
     pub vapid_privkey: Vec<u8>,
+    pub retry_policy: RetryPolicy,
+    pub push_client: PushClient,
+
+    /// Queue for messages sent asynchronously. If not set, they are delivered before responding.
+    pub delivery_queue: Option<DeliveryQueue>,
+
+    /// How long a send's idempotency key prevents repeats of it from being sent.
+    pub idempotency_window: Duration,
 }
 
+/// Default for `ServerState::idempotency_window`.
+const DEFAULT_IDEMPOTENCY_WINDOW_SECS: i64 = 24 * 60 * 60;
+
 impl ServerState {
-    pub async fn new() -> Self {
-        let pool = deadpool::managed::Pool::<NotifyDatabaseManager>::builder(NotifyDatabaseManager)
-            .build()
-            .unwrap();
+    pub fn new(
+        database: Arc<dyn NotifyDatabase>,
+        server_base: String,
+        vapid_pubkey: String,
+        vapid_privkey: Vec<u8>,
+    ) -> Self {
+        ServerState {
+            database,
+            vapid_privkey,
+            vapid_pubkey,
+            server_base,
+            retry_policy: RetryPolicy::default(),
+            push_client: push_client(),
+            delivery_queue: None,
+            idempotency_window: Duration::seconds(DEFAULT_IDEMPOTENCY_WINDOW_SECS),
+        }
+    }
 
+    /// Construct server state, reading the server URL and VAPID keys from the environment.
+    pub fn from_env(database: Arc<dyn NotifyDatabase>) -> Self {
         let vapid_pubkey =
             std::env::var("NOTIFY_VAPID_PUBKEY").expect("Expected NOTIFY_VAPID_PUBKEY env var.");
         let vapid_privkey_b64 =
@@ -29,22 +58,43 @@ impl ServerState {
         let vapid_privkey = base64::decode_config(&vapid_privkey_b64, URL_SAFE)
             .expect("Could not decode VAPID private key as base64.");
 
+        let idempotency_window_secs = match std::env::var("NOTIFY_IDEMPOTENCY_WINDOW_SECS") {
+            Ok(secs) => secs
+                .parse()
+                .expect("Expected NOTIFY_IDEMPOTENCY_WINDOW_SECS to be an integer."),
+            Err(_) => DEFAULT_IDEMPOTENCY_WINDOW_SECS,
+        };
+
         ServerState {
-            pool,
-            vapid_privkey,
-            vapid_pubkey,
-            server_base,
+            retry_policy: RetryPolicy::from_env(),
+            idempotency_window: Duration::seconds(idempotency_window_secs),
+            ..ServerState::new(database, server_base, vapid_pubkey, vapid_privkey)
         }
     }
 
-    pub async fn db(&self) -> Result<Object<NotifyDatabaseManager>, PoolError<Infallible>> {
-        self.pool.get().await
+    /// Start background workers to deliver messages sent asynchronously.
+    pub fn with_delivery_queue(mut self, workers: usize) -> Self {
+        self.delivery_queue = Some(DeliveryQueue::start(self.clone(), workers));
+        self
+    }
+
+    pub fn db(&self) -> &dyn NotifyDatabase {
+        &*self.database
     }
 
     pub fn channel_page_url(&self, channel_id: &str) -> String {
         format!("{}/c/{}", self.server_base, channel_id)
     }
 
+    /// URL opened by clicking a notification from the channel, if the message doesn't
+    /// give its own.
+    pub fn default_action_url(&self, channel_id: &str, channel: &Channel) -> String {
+        channel
+            .default_action
+            .clone()
+            .unwrap_or_else(|| self.channel_page_url(channel_id))
+    }
+
     pub fn endpoint_url(&self, channel_id: &str) -> String {
         format!("{}/{}", self.server_base, channel_id)
     }
@@ -1,56 +1,723 @@
+use std::convert::TryFrom;
+use std::fmt::Display;
 use std::io::Cursor;
+use std::time::{Duration, SystemTime};
 
-use crate::model::Subscription;
+use crate::model::{Channel, MessageAction, Priority, Subscription};
 use anyhow::Result;
-use serde::{Deserialize, Serialize};
+use axum::http::{header::RETRY_AFTER, HeaderValue, Request, StatusCode};
+use chrono::{DateTime, Utc};
+use hyper::{client::HttpConnector, Body, Client};
+use hyper_tls::HttpsConnector;
+use serde::{de, Deserialize, Deserializer, Serialize};
 use web_push::{
-    ContentEncoding, SubscriptionInfo, VapidSignatureBuilder, WebPushClient, WebPushMessageBuilder,
+    request_builder, ContentEncoding, SubscriptionInfo, VapidSignatureBuilder, WebPushError,
+    WebPushMessageBuilder,
 };
 
-#[derive(Serialize, PartialEq, Debug)]
+/// HTTP client used to make requests to push services.
+pub type PushClient = Client<HttpsConnector<HttpConnector>>;
+
+pub fn push_client() -> PushClient {
+    Client::builder().build(HttpsConnector::new())
+}
+
+/// The push service responded 429 Too Many Requests.
+#[derive(Debug)]
+pub struct TooManyRequests {
+    pub retry_after: Option<Duration>,
+}
+
+impl Display for TooManyRequests {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Too many requests")
+    }
+}
+
+impl std::error::Error for TooManyRequests {}
+
+/// How a failed call to `send_message` should be handled.
+#[derive(Debug, PartialEq)]
+pub enum SendFailure {
+    /// The subscription is permanently gone (the push service responded 404 Not Found
+    /// or 410 Gone). Contains a description of why.
+    Expired(String),
+
+    /// The failure may not happen again, so the message may be delivered by retrying,
+    /// after the delay requested by the push service, if any.
+    Transient(Option<Duration>),
+
+    /// Retrying would fail in the same way.
+    Permanent,
+}
+
+impl SendFailure {
+    pub fn classify(error: &anyhow::Error) -> Self {
+        if let Some(e) = error.downcast_ref::<WebPushError>() {
+            match e {
+                WebPushError::EndpointNotFound | WebPushError::EndpointNotValid => {
+                    SendFailure::Expired(e.to_string())
+                }
+                WebPushError::ServerError(retry_after) => SendFailure::Transient(*retry_after),
+                _ => SendFailure::Permanent,
+            }
+        } else if let Some(e) = error.downcast_ref::<TooManyRequests>() {
+            SendFailure::Transient(e.retry_after)
+        } else if error.is::<hyper::Error>() {
+            SendFailure::Transient(None)
+        } else {
+            SendFailure::Permanent
+        }
+    }
+}
+
+/// Parse a Retry-After header, which is either a number of seconds or an HTTP date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date: SystemTime = chrono::DateTime::parse_from_rfc2822(value).ok()?.into();
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+/// How urgently the push service should deliver a message, which it may use to conserve
+/// the device's battery by delaying low-urgency messages.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Urgency {
+    VeryLow,
+    Low,
+    Normal,
+    High,
+}
+
+impl Urgency {
+    fn header_value(&self) -> &'static str {
+        match self {
+            Urgency::VeryLow => "very-low",
+            Urgency::Low => "low",
+            Urgency::Normal => "normal",
+            Urgency::High => "high",
+        }
+    }
+}
+
+/// Options that control how the push service handles a message. These are sent as
+/// headers of the push request rather than as part of the payload.
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq, Debug)]
+pub struct PushOptions {
+    /// Seconds the push service should keep the message if the device is offline.
+    pub ttl: Option<u32>,
+    pub urgency: Option<Urgency>,
+
+    /// Messages with the same topic replace each other while waiting to be delivered.
+    pub topic: Option<String>,
+}
+
+/// How a message of a given priority is delivered and shown, where the sender hasn't
+/// set these explicitly.
+#[derive(Default)]
+struct PriorityBehaviour {
+    urgency: Option<Urgency>,
+    ttl: Option<u32>,
+    silent: Option<bool>,
+    vibrate: Option<bool>,
+}
+
+impl PriorityBehaviour {
+    fn of(priority: Option<Priority>) -> Self {
+        match priority {
+            Some(Priority::Min) => PriorityBehaviour {
+                urgency: Some(Urgency::VeryLow),
+                ttl: Some(60 * 60),
+                silent: Some(true),
+                vibrate: Some(false),
+            },
+            Some(Priority::Low) => PriorityBehaviour {
+                urgency: Some(Urgency::Low),
+                ttl: Some(24 * 60 * 60),
+                silent: None,
+                vibrate: Some(false),
+            },
+            Some(Priority::Default) | None => PriorityBehaviour::default(),
+            Some(Priority::High) => PriorityBehaviour {
+                urgency: Some(Urgency::High),
+                ttl: Some(7 * 24 * 60 * 60),
+                silent: Some(false),
+                vibrate: Some(true),
+            },
+            // Kept by the push service for as long as it allows (the default TTL), and
+            // shown by the service worker until the user dismisses it.
+            Some(Priority::Urgent) => PriorityBehaviour {
+                urgency: Some(Urgency::High),
+                ttl: None,
+                silent: Some(false),
+                vibrate: Some(true),
+            },
+        }
+    }
+}
+
+/// Optional parts of the notification shown by the service worker.
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq, Debug)]
+pub struct NotificationOptions {
+    /// URL of an image shown beside the notification, in place of the notify.run icon.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+
+    /// URL of a larger image shown in the body of the notification.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+
+    /// URL of a small monochrome image shown in the status bar.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub badge: Option<String>,
+
+    /// A notification replaces any earlier notification from the channel with the same tag.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+
+    /// Alert the user again when replacing a notification with the same tag.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub renotify: bool,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct MessagePayloadData {
     /// URL to open when notification is clicked.
     action: String,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub actions: Vec<MessageAction>,
 }
 
-#[derive(Serialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct MessagePayload {
     pub message: String,
-    vibrate: bool,
-    silent: bool,
+
+    /// Set if the sender gave a title or body separately from the message. Otherwise, the
+    /// service worker splits the message into a title and body at the first newline.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    /// Left unset by the sender to use the channel's defaults.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    vibrate: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    silent: Option<bool>,
     channel: String,
-    data: MessagePayloadData,
+    pub data: MessagePayloadData,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<Priority>,
+
+    #[serde(flatten)]
+    pub notification: NotificationOptions,
+
+    #[serde(skip)]
+    pub options: PushOptions,
+
+    /// Shorten the message if it is too large to send, instead of rejecting it.
+    #[serde(skip)]
+    pub truncate: bool,
+
+    /// Repeats of a send with the same key are not sent again. May also be given as a header.
+    #[serde(skip)]
+    pub idempotency_key: Option<String>,
+
+    /// Time the sender asked for the message to be delivered, if not immediately.
+    #[serde(skip)]
+    pub deliver_at: Option<DateTime<Utc>>,
 }
 
-#[derive(Deserialize)]
+/// A payload stored to be sent later, with the push options that are not part of it.
+#[derive(Serialize, Deserialize)]
+struct StoredPayload<P> {
+    payload: P,
+    options: PushOptions,
+}
+
+/// Maximum size of a serialized payload. Push services accept about 4KB once encrypted,
+/// and the web-push crate refuses to encrypt anything larger than this.
+pub const MAX_PAYLOAD_SIZE: usize = 3052;
+
+/// Appended to text shortened to fit in a payload.
+const ELLIPSIS: char = '\u{2026}';
+
+/// Maximum time (in days) ahead that a message can be scheduled.
+const MAX_SCHEDULE_DAYS: i64 = 30;
+
+/// Maximum length of an idempotency key.
+const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 255;
+
+/// Maximum length of a topic, which may only contain URL-safe base64 characters.
+const MAX_TOPIC_LENGTH: usize = 32;
+
+/// Maximum length of a notification tag.
+const MAX_TAG_LENGTH: usize = 64;
+
+/// Maximum number of action buttons on a notification. Chrome shows at most two.
+const MAX_ACTIONS: usize = 2;
+
+/// Maximum length of the label of an action button.
+const MAX_ACTION_LABEL_LENGTH: usize = 32;
+
+pub fn is_http_url(url: &str) -> bool {
+    url.parse::<axum::http::Uri>()
+        .ok()
+        .and_then(|uri| uri.scheme_str().map(|scheme| scheme.to_string()))
+        .is_some_and(|scheme| scheme == "http" || scheme == "https")
+}
+
+/// Problems with a message sent to a channel, to be reported back to the sender.
+#[derive(Serialize, PartialEq, Debug)]
+pub struct ValidationErrors {
+    pub errors: Vec<String>,
+}
+
+impl ValidationErrors {
+    pub fn single(error: impl Display) -> Self {
+        ValidationErrors {
+            errors: vec![error.to_string()],
+        }
+    }
+}
+
+/// Fields of a message, sent either as a form or as a JSON object.
+#[derive(Deserialize, Default)]
 struct MessageFormData {
+    #[serde(default)]
     message: String,
+    title: Option<String>,
+    body: Option<String>,
     action: Option<String>,
+
+    /// Only supported in JSON bodies, since forms can't represent a list of buttons.
+    #[serde(default)]
+    actions: Vec<MessageAction>,
+    icon: Option<String>,
+    image: Option<String>,
+    badge: Option<String>,
+    tag: Option<String>,
+    #[serde(default)]
+    renotify: bool,
+    vibrate: Option<bool>,
+    silent: Option<bool>,
+    priority: Option<Priority>,
+    ttl: Option<u32>,
+    urgency: Option<Urgency>,
+    topic: Option<String>,
+    #[serde(default)]
+    truncate: bool,
+    idempotency_key: Option<String>,
+
+    /// Deliver the message at the given time, or after the given delay, instead of now.
+    deliver_at: Option<DateTime<Utc>>,
+    delay: Option<Delay>,
+}
+
+/// A delay before delivering a message, given as a number of seconds or as a string like
+/// `90s`, `15m`, `2h` or `1d12h`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Delay(chrono::Duration);
+
+impl Delay {
+    fn parse(value: &str) -> Option<Self> {
+        if let Ok(seconds) = value.parse::<u32>() {
+            return Some(Delay(chrono::Duration::seconds(seconds.into())));
+        }
+
+        if value.is_empty() {
+            return None;
+        }
+
+        let mut total = chrono::Duration::zero();
+        let mut rest = value;
+        while !rest.is_empty() {
+            let digits = rest.find(|c: char| !c.is_ascii_digit())?;
+            let amount: i32 = rest[..digits].parse().ok()?;
+            let unit = match rest[digits..].chars().next()? {
+                's' => chrono::Duration::seconds(1),
+                'm' => chrono::Duration::minutes(1),
+                'h' => chrono::Duration::hours(1),
+                'd' => chrono::Duration::days(1),
+                _ => return None,
+            };
+
+            total = total.checked_add(&(unit * amount))?;
+            rest = &rest[digits + 1..];
+        }
+
+        Some(Delay(total))
+    }
+}
+
+impl<'de> Deserialize<'de> for Delay {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct DelayVisitor;
+
+        impl<'de> de::Visitor<'de> for DelayVisitor {
+            type Value = Delay;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "a number of seconds or a duration like \"2h\"")
+            }
+
+            fn visit_u64<E: de::Error>(self, seconds: u64) -> Result<Delay, E> {
+                i64::try_from(seconds)
+                    .ok()
+                    .and_then(chrono::Duration::try_seconds)
+                    .map(Delay)
+                    .ok_or_else(|| E::invalid_value(de::Unexpected::Unsigned(seconds), &self))
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Delay, E> {
+                Delay::parse(value)
+                    .ok_or_else(|| E::invalid_value(de::Unexpected::Str(value), &self))
+            }
+        }
+
+        deserializer.deserialize_any(DelayVisitor)
+    }
+}
+
+pub fn check_idempotency_key(key: &str) -> Result<(), ValidationErrors> {
+    if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LENGTH {
+        return Err(ValidationErrors::single(format!(
+            "idempotency_key: must be 1 to {} bytes",
+            MAX_IDEMPOTENCY_KEY_LENGTH
+        )));
+    }
+
+    Ok(())
+}
+
+/// Shorten text by at least `excess` bytes, ending it with an ellipsis. Returns false if
+/// the text can't be shortened any further.
+fn truncate_with_ellipsis(text: &mut String, excess: usize) -> bool {
+    let mut end = text.len().saturating_sub(excess + ELLIPSIS.len_utf8());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    let mut shortened = text[..end].to_string();
+    shortened.push(ELLIPSIS);
+    if shortened.len() >= text.len() {
+        return false;
+    }
+
+    *text = shortened;
+    true
+}
+
+impl MessageFormData {
+    /// Time the message should be delivered, if the sender gave one. Only call this
+    /// once the message is validated, since a long delay could overflow.
+    fn deliver_time(&self) -> Option<DateTime<Utc>> {
+        self.deliver_at
+            .or_else(|| self.delay.map(|Delay(delay)| Utc::now() + delay))
+    }
+
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = Vec::new();
+
+        if self.message.is_empty() && self.title.is_none() && self.body.is_none() {
+            errors.push("message: must not be empty unless title or body is given".to_string());
+        }
+
+        let urls = [
+            ("action", &self.action),
+            ("icon", &self.icon),
+            ("image", &self.image),
+            ("badge", &self.badge),
+        ];
+        for (field, url) in urls {
+            if url.as_deref().is_some_and(|url| !is_http_url(url)) {
+                errors.push(format!("{}: must be an http or https URL", field));
+            }
+        }
+
+        if self.actions.len() > MAX_ACTIONS {
+            errors.push(format!("actions: at most {} are allowed", MAX_ACTIONS));
+        }
+
+        for (index, action) in self.actions.iter().enumerate() {
+            let label_length = action.label.chars().count();
+            if label_length == 0 || label_length > MAX_ACTION_LABEL_LENGTH {
+                errors.push(format!(
+                    "actions[{}].label: must be 1 to {} characters",
+                    index, MAX_ACTION_LABEL_LENGTH
+                ));
+            }
+            if !is_http_url(&action.url) {
+                errors.push(format!(
+                    "actions[{}].url: must be an http or https URL",
+                    index
+                ));
+            }
+        }
+
+        if let Some(tag) = &self.tag {
+            if tag.is_empty() || tag.chars().count() > MAX_TAG_LENGTH {
+                errors.push(format!("tag: must be 1 to {} characters", MAX_TAG_LENGTH));
+            }
+        }
+
+        if let Some(Err(key_errors)) = self.idempotency_key.as_deref().map(check_idempotency_key) {
+            errors.extend(key_errors.errors);
+        }
+
+        if self.deliver_at.is_some() && self.delay.is_some() {
+            errors.push("delay: cannot be given along with deliver_at".to_string());
+        }
+
+        let max_delay = chrono::Duration::days(MAX_SCHEDULE_DAYS);
+        if self
+            .deliver_at
+            .is_some_and(|time| time > Utc::now() + max_delay)
+        {
+            errors.push(format!(
+                "deliver_at: must be at most {} days from now",
+                MAX_SCHEDULE_DAYS
+            ));
+        }
+        if self.delay.is_some_and(|Delay(delay)| delay > max_delay) {
+            errors.push(format!("delay: must be at most {} days", MAX_SCHEDULE_DAYS));
+        }
+
+        if self.renotify && self.tag.is_none() {
+            errors.push("renotify: requires a tag".to_string());
+        }
+
+        if let Some(topic) = &self.topic {
+            let is_url_safe = topic
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+            if topic.is_empty() || topic.len() > MAX_TOPIC_LENGTH || !is_url_safe {
+                errors.push(format!(
+                    "topic: must be 1 to {} letters, digits, '-' or '_'",
+                    MAX_TOPIC_LENGTH
+                ));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ValidationErrors { errors })
+        }
+    }
 }
 
 impl MessagePayload {
-    pub fn parse_new(message: &str, channel: &str, default_action: &str) -> Self {
-        let message = match serde_urlencoded::from_str::<MessageFormData>(message) {
-            Ok(message) => message,
-            Err(_) => MessageFormData {
+    /// Parse a message sent as a form with a `message` field, or otherwise as plain text.
+    pub fn parse_new(
+        message: &str,
+        channel: &str,
+        default_action: &str,
+    ) -> Result<Self, ValidationErrors> {
+        let is_form =
+            serde_urlencoded::from_str::<Vec<(String, String)>>(message).is_ok_and(|fields| {
+                fields
+                    .iter()
+                    .any(|(key, _)| matches!(key.as_str(), "message" | "title" | "body"))
+            });
+
+        let message = if is_form {
+            serde_urlencoded::from_str::<MessageFormData>(message)
+                .map_err(ValidationErrors::single)?
+        } else {
+            MessageFormData {
                 message: message.to_string(),
-                action: None,
+                ..MessageFormData::default()
+            }
+        };
+
+        Self::from_data(message, channel, default_action)
+    }
+
+    /// Parse a message sent as a JSON object.
+    pub fn parse_json(
+        message: &str,
+        channel: &str,
+        default_action: &str,
+    ) -> Result<Self, ValidationErrors> {
+        let message: MessageFormData =
+            serde_json::from_str(message).map_err(ValidationErrors::single)?;
+
+        Self::from_data(message, channel, default_action)
+    }
+
+    /// A notice sent by the server itself, rather than by a sender.
+    pub fn notice(
+        title: &str,
+        body: &str,
+        priority: Priority,
+        channel: &str,
+        default_action: &str,
+    ) -> Result<Self, ValidationErrors> {
+        let message = MessageFormData {
+            title: Some(title.to_string()),
+            body: Some(body.to_string()),
+            priority: Some(priority),
+            ..MessageFormData::default()
+        };
+
+        Self::from_data(message, channel, default_action)
+    }
+
+    /// Fill in flags the sender didn't set, from the message's priority or otherwise
+    /// from the channel's defaults, along with the channel's default icon.
+    pub fn apply_channel_defaults(&mut self, channel: &Channel) {
+        let behaviour = PriorityBehaviour::of(self.priority);
+        let vibrate = self.vibrate.or(behaviour.vibrate);
+        let silent = self.silent.or(behaviour.silent);
+
+        self.vibrate = Some(vibrate.unwrap_or(channel.default_vibrate));
+        self.silent = Some(silent.unwrap_or(channel.default_silent));
+
+        if self.notification.icon.is_none() {
+            self.notification.icon = channel.default_icon.clone();
+        }
+    }
+
+    fn serialized_size(&self) -> usize {
+        serde_json::to_vec(self).map_or(0, |json| json.len())
+    }
+
+    /// Make sure the payload fits in a push message, by truncating its text if the sender
+    /// allowed it.
+    pub fn fit_size(&mut self) -> Result<(), ValidationErrors> {
+        loop {
+            let size = self.serialized_size();
+            if size <= MAX_PAYLOAD_SIZE {
+                return Ok(());
+            }
+
+            // The body, if given, is repeated in the message, so both are shortened.
+            let excess = size - MAX_PAYLOAD_SIZE;
+            let mut shortened = false;
+            if self.truncate {
+                if let Some(body) = &mut self.body {
+                    let excess = excess.div_ceil(2);
+                    shortened |= truncate_with_ellipsis(body, excess);
+                    shortened |= truncate_with_ellipsis(&mut self.message, excess);
+                } else {
+                    shortened |= truncate_with_ellipsis(&mut self.message, excess);
+                }
+            }
+
+            if !shortened {
+                return Err(ValidationErrors::single(format!(
+                    "message: payload is {} bytes, more than the {} allowed{}",
+                    size,
+                    MAX_PAYLOAD_SIZE,
+                    if self.truncate {
+                        ""
+                    } else {
+                        "; set truncate to shorten it instead"
+                    }
+                )));
+            }
+        }
+    }
+
+    fn from_data(
+        message: MessageFormData,
+        channel: &str,
+        default_action: &str,
+    ) -> Result<Self, ValidationErrors> {
+        message.validate()?;
+
+        let deliver_at = message.deliver_time();
+        let text = &message.message;
+        let (title, body) = match (message.title, message.body) {
+            (None, None) => (None, None),
+            (Some(title), body) => {
+                let body = body.unwrap_or_else(|| text.clone());
+                (Some(title), Some(body))
+            }
+            (None, Some(body)) => match text.lines().next().filter(|line| !line.is_empty()) {
+                Some(title) => (Some(title.to_string()), Some(body)),
+                // Nothing else to title the notification with, so split the body like a plain message.
+                None => {
+                    let (title, rest) = body.split_once('\n').unwrap_or((&body, ""));
+                    let rest = Some(rest.to_string()).filter(|rest| !rest.is_empty());
+                    (Some(title.to_string()), rest)
+                }
             },
         };
 
-        MessagePayload {
-            message: message.message,
+        // Keep a plain-text version of the message for the message log and older service workers.
+        let text = if message.message.is_empty() {
+            [title.as_deref(), body.as_deref()]
+                .iter()
+                .flatten()
+                .filter(|part| !part.is_empty())
+                .copied()
+                .collect::<Vec<_>>()
+                .join("\n")
+        } else {
+            message.message
+        };
+
+        Ok(MessagePayload {
+            message: text,
+            title,
+            body,
             channel: channel.to_string(),
-            silent: false,
-            vibrate: false,
+            silent: message.silent,
+            vibrate: message.vibrate,
+            priority: message.priority,
             data: MessagePayloadData {
                 action: message.action.unwrap_or_else(|| default_action.to_string()),
+                actions: message.actions,
             },
-        }
+            notification: NotificationOptions {
+                icon: message.icon,
+                image: message.image,
+                badge: message.badge,
+                tag: message.tag,
+                renotify: message.renotify,
+            },
+            options: PushOptions {
+                ttl: message.ttl,
+                urgency: message.urgency,
+                topic: message.topic,
+            },
+            truncate: message.truncate,
+            idempotency_key: message.idempotency_key,
+            deliver_at,
+        })
+    }
+
+    /// Serialize the payload, including its push options, to be sent later.
+    pub fn to_stored(&self) -> Result<String> {
+        let stored = StoredPayload {
+            payload: self,
+            options: self.options.clone(),
+        };
+
+        Ok(serde_json::to_string(&stored)?)
+    }
+
+    /// Read a payload serialized by `to_stored`.
+    pub fn from_stored(stored: &str) -> Result<Self> {
+        let StoredPayload::<MessagePayload> {
+            mut payload,
+            options,
+        } = serde_json::from_str(stored)?;
+        payload.options = options;
+
+        Ok(payload)
     }
 }
 
 pub async fn send_message(
+    client: &PushClient,
     message: &MessagePayload,
     subscription: &Subscription,
     vapid_privkey: &[u8],
@@ -74,10 +741,42 @@ pub async fn send_message(
     builder.set_payload(ContentEncoding::Aes128Gcm, payload_json.as_bytes());
     builder.set_vapid_signature(signature);
 
-    let client = WebPushClient::new()?;
-    client.send(builder.build()?).await?;
+    // Options set explicitly by the sender take precedence over those implied by priority.
+    let behaviour = PriorityBehaviour::of(message.priority);
+    if let Some(ttl) = message.options.ttl.or(behaviour.ttl) {
+        builder.set_ttl(ttl);
+    }
 
-    Ok(())
+    let mut request: Request<Body> = request_builder::build_request(builder.build()?);
+    if let Some(urgency) = message.options.urgency.or(behaviour.urgency) {
+        request
+            .headers_mut()
+            .insert("urgency", HeaderValue::from_static(urgency.header_value()));
+    }
+    if let Some(topic) = &message.options.topic {
+        request
+            .headers_mut()
+            .insert("topic", HeaderValue::from_str(topic)?);
+    }
+
+    let response = client.request(request).await?;
+
+    let retry_after = response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_retry_after);
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        return Err(TooManyRequests { retry_after }.into());
+    }
+
+    match request_builder::parse_response(status, body.to_vec()) {
+        Err(WebPushError::ServerError(None)) => Err(WebPushError::ServerError(retry_after).into()),
+        result => Ok(result?),
+    }
 }
 
 #[cfg(test)]
@@ -86,17 +785,27 @@ mod test {
 
     #[test]
     pub fn test_parse_plain_message() {
-        let payload = MessagePayload::parse_new("my message", "abcdef", "http://blah/c/abcdef");
+        let payload =
+            MessagePayload::parse_new("my message", "abcdef", "http://blah/c/abcdef").unwrap();
 
         assert_eq!(
             MessagePayload {
                 message: "my message".to_string(),
+                title: None,
+                body: None,
                 channel: "abcdef".to_string(),
-                vibrate: false,
-                silent: false,
+                vibrate: None,
+                silent: None,
                 data: MessagePayloadData {
-                    action: "http://blah/c/abcdef".to_string()
-                }
+                    action: "http://blah/c/abcdef".to_string(),
+                    actions: Vec::new(),
+                },
+                priority: None,
+                notification: NotificationOptions::default(),
+                options: PushOptions::default(),
+                truncate: false,
+                idempotency_key: None,
+                deliver_at: None,
             },
             payload
         );
@@ -108,17 +817,27 @@ mod test {
             "message=this+is+my+message",
             "abcdef",
             "http://blah/c/abcdef",
-        );
+        )
+        .unwrap();
 
         assert_eq!(
             MessagePayload {
                 message: "this is my message".to_string(),
+                title: None,
+                body: None,
                 channel: "abcdef".to_string(),
-                vibrate: false,
-                silent: false,
+                vibrate: None,
+                silent: None,
                 data: MessagePayloadData {
-                    action: "http://blah/c/abcdef".to_string()
-                }
+                    action: "http://blah/c/abcdef".to_string(),
+                    actions: Vec::new(),
+                },
+                priority: None,
+                notification: NotificationOptions::default(),
+                options: PushOptions::default(),
+                truncate: false,
+                idempotency_key: None,
+                deliver_at: None,
             },
             payload
         );
@@ -130,19 +849,413 @@ mod test {
             "message=this+is+my+message&action=https://www.example.com/",
             "abcdef",
             "http://blah/c/abcdef",
-        );
+        )
+        .unwrap();
 
         assert_eq!(
             MessagePayload {
                 message: "this is my message".to_string(),
+                title: None,
+                body: None,
                 channel: "abcdef".to_string(),
-                vibrate: false,
-                silent: false,
+                vibrate: None,
+                silent: None,
                 data: MessagePayloadData {
-                    action: "https://www.example.com/".to_string()
-                }
+                    action: "https://www.example.com/".to_string(),
+                    actions: Vec::new(),
+                },
+                priority: None,
+                notification: NotificationOptions::default(),
+                options: PushOptions::default(),
+                truncate: false,
+                idempotency_key: None,
+                deliver_at: None,
             },
             payload
         );
     }
+
+    #[test]
+    pub fn test_parse_message_with_push_options() {
+        let payload = MessagePayload::parse_new(
+            "message=status&ttl=3600&urgency=very-low&topic=build-status",
+            "abcdef",
+            "http://blah/c/abcdef",
+        )
+        .unwrap();
+
+        assert_eq!("status", payload.message);
+        assert_eq!(
+            PushOptions {
+                ttl: Some(3600),
+                urgency: Some(Urgency::VeryLow),
+                topic: Some("build-status".to_string()),
+            },
+            payload.options
+        );
+    }
+
+    #[test]
+    pub fn test_parse_title_and_body() {
+        let payload = MessagePayload::parse_new(
+            "title=Backup+failed&body=Disk+full+on+%2Fdev%2Fsda1",
+            "abcdef",
+            "http://blah/c/abcdef",
+        )
+        .unwrap();
+        assert_eq!("Backup failed\nDisk full on /dev/sda1", payload.message);
+        assert_eq!(Some("Backup failed"), payload.title.as_deref());
+        assert_eq!(Some("Disk full on /dev/sda1"), payload.body.as_deref());
+
+        let payload = MessagePayload::parse_json(
+            r#"{"title": "Nightly build", "message": "All 1204 tests passed on the main branch"}"#,
+            "abcdef",
+            "http://blah/c/abcdef",
+        )
+        .unwrap();
+        assert_eq!(Some("Nightly build"), payload.title.as_deref());
+        assert_eq!(
+            Some("All 1204 tests passed on the main branch"),
+            payload.body.as_deref()
+        );
+
+        let payload = MessagePayload::parse_new(
+            "message=Build+failed&body=See+the+log",
+            "abcdef",
+            "http://blah/c/abcdef",
+        )
+        .unwrap();
+        assert_eq!(Some("Build failed"), payload.title.as_deref());
+        assert_eq!(Some("See the log"), payload.body.as_deref());
+
+        let payload = MessagePayload::parse_json(
+            r#"{"body": "Deployed\nv1.2 is live"}"#,
+            "abcdef",
+            "http://blah/c/abcdef",
+        )
+        .unwrap();
+        assert_eq!("Deployed\nv1.2 is live", payload.message);
+        assert_eq!(Some("Deployed"), payload.title.as_deref());
+        assert_eq!(Some("v1.2 is live"), payload.body.as_deref());
+
+        let payload =
+            MessagePayload::parse_json(r#"{"body": "Deployed"}"#, "abcdef", "http://blah/c/abcdef")
+                .unwrap();
+        assert_eq!("Deployed", payload.message);
+        assert_eq!(Some("Deployed"), payload.title.as_deref());
+        assert_eq!(None, payload.body);
+    }
+
+    #[test]
+    pub fn test_parse_notification_options() {
+        let payload = MessagePayload::parse_new(
+            "message=CPU+high&image=https://grafana.example.com/render/cpu.png&tag=cpu&renotify=true",
+            "abcdef",
+            "http://blah/c/abcdef",
+        )
+        .unwrap();
+        assert_eq!(
+            NotificationOptions {
+                image: Some("https://grafana.example.com/render/cpu.png".to_string()),
+                tag: Some("cpu".to_string()),
+                renotify: true,
+                ..NotificationOptions::default()
+            },
+            payload.notification
+        );
+
+        let json = serde_json::to_value(&payload).unwrap();
+        assert_eq!("cpu", json["tag"]);
+        assert!(json.get("icon").is_none());
+
+        let result = MessagePayload::parse_json(
+            r#"{"message": "hi", "icon": "file:///etc/passwd", "renotify": true}"#,
+            "abcdef",
+            "http://blah/c/abcdef",
+        );
+        assert_eq!(
+            vec![
+                "icon: must be an http or https URL".to_string(),
+                "renotify: requires a tag".to_string()
+            ],
+            result.unwrap_err().errors
+        );
+    }
+
+    #[test]
+    pub fn test_parse_actions() {
+        let payload = MessagePayload::parse_json(
+            r#"{"message": "Deployed v1.2", "actions": [
+                {"label": "Open logs", "url": "https://logs.example.com/"},
+                {"label": "Rollback dashboard", "url": "https://deploy.example.com/rollback"}
+            ]}"#,
+            "abcdef",
+            "http://blah/c/abcdef",
+        )
+        .unwrap();
+        assert_eq!(2, payload.data.actions.len());
+        assert_eq!("Open logs", payload.data.actions[0].label);
+
+        let json = serde_json::to_value(&payload).unwrap();
+        assert_eq!(
+            "https://deploy.example.com/rollback",
+            json["data"]["actions"][1]["url"]
+        );
+
+        let result = MessagePayload::parse_json(
+            r#"{"message": "hi", "actions": [
+                {"label": "", "url": "https://a.example.com/"},
+                {"label": "b", "url": "b.example.com"},
+                {"label": "c", "url": "https://c.example.com/"}
+            ]}"#,
+            "abcdef",
+            "http://blah/c/abcdef",
+        );
+        assert_eq!(3, result.unwrap_err().errors.len());
+    }
+
+    #[test]
+    pub fn test_vibrate_and_silent() {
+        let channel = Channel {
+            default_vibrate: true,
+            ..Default::default()
+        };
+
+        let mut payload =
+            MessagePayload::parse_new("message=hi&silent=true", "abcdef", "http://blah/c/abcdef")
+                .unwrap();
+        payload.apply_channel_defaults(&channel);
+        assert_eq!(Some(true), payload.vibrate);
+        assert_eq!(Some(true), payload.silent);
+
+        let mut payload = MessagePayload::parse_json(
+            r#"{"message": "hi", "vibrate": false}"#,
+            "abcdef",
+            "http://blah/c/abcdef",
+        )
+        .unwrap();
+        payload.apply_channel_defaults(&channel);
+        assert_eq!(Some(false), payload.vibrate);
+        assert_eq!(Some(false), payload.silent);
+    }
+
+    #[test]
+    pub fn test_default_icon() {
+        let channel = Channel {
+            default_icon: Some("https://example.com/channel.png".to_string()),
+            ..Default::default()
+        };
+
+        let mut payload =
+            MessagePayload::parse_new("hi", "abcdef", "http://blah/c/abcdef").unwrap();
+        payload.apply_channel_defaults(&channel);
+        assert_eq!(
+            Some("https://example.com/channel.png"),
+            payload.notification.icon.as_deref()
+        );
+
+        let mut payload = MessagePayload::parse_json(
+            r#"{"message": "hi", "icon": "https://example.com/message.png"}"#,
+            "abcdef",
+            "http://blah/c/abcdef",
+        )
+        .unwrap();
+        payload.apply_channel_defaults(&channel);
+        assert_eq!(
+            Some("https://example.com/message.png"),
+            payload.notification.icon.as_deref()
+        );
+    }
+
+    #[test]
+    pub fn test_priority() {
+        let channel = Channel::default();
+
+        let mut payload =
+            MessagePayload::parse_new("message=hi&priority=min", "abcdef", "http://blah/c/abcdef")
+                .unwrap();
+        payload.apply_channel_defaults(&channel);
+        assert_eq!(Some(Priority::Min), payload.priority);
+        assert_eq!(Some(true), payload.silent);
+
+        // Explicit flags override the priority.
+        let mut payload = MessagePayload::parse_json(
+            r#"{"message": "hi", "priority": "urgent", "vibrate": false}"#,
+            "abcdef",
+            "http://blah/c/abcdef",
+        )
+        .unwrap();
+        payload.apply_channel_defaults(&channel);
+        assert_eq!(Some(false), payload.vibrate);
+        assert_eq!(Some(false), payload.silent);
+        assert_eq!(
+            "urgent",
+            serde_json::to_value(&payload).unwrap()["priority"]
+        );
+    }
+
+    #[test]
+    pub fn test_fit_size() {
+        let long_text = "é".repeat(MAX_PAYLOAD_SIZE);
+
+        let mut payload =
+            MessagePayload::parse_new(&long_text, "abcdef", "http://blah/c/abcdef").unwrap();
+        let errors = payload.fit_size().unwrap_err().errors;
+        assert!(errors[0].contains("truncate"));
+
+        let mut payload = MessagePayload::parse_json(
+            &serde_json::json!({"title": "Log", "body": long_text, "truncate": true}).to_string(),
+            "abcdef",
+            "http://blah/c/abcdef",
+        )
+        .unwrap();
+        payload.fit_size().unwrap();
+        assert!(payload.serialized_size() <= MAX_PAYLOAD_SIZE);
+        assert!(payload.body.unwrap().ends_with(ELLIPSIS));
+        assert!(payload.message.starts_with("Log\n"));
+
+        // Text that fits is left alone.
+        let mut payload =
+            MessagePayload::parse_new("message=hi&truncate=true", "abcdef", "http://blah/c/abcdef")
+                .unwrap();
+        payload.fit_size().unwrap();
+        assert_eq!("hi", payload.message);
+    }
+
+    #[test]
+    pub fn test_parse_delay() {
+        let hours = |hours| Some(Delay(chrono::Duration::hours(hours)));
+        assert_eq!(hours(2), Delay::parse("7200"));
+        assert_eq!(hours(2), Delay::parse("2h"));
+        assert_eq!(hours(2), Delay::parse("1h60m"));
+        assert_eq!(hours(36), Delay::parse("1d12h"));
+        assert_eq!(None, Delay::parse("2 hours"));
+        assert_eq!(None, Delay::parse("h"));
+        assert_eq!(None, Delay::parse(""));
+    }
+
+    #[test]
+    pub fn test_parse_schedule() {
+        let payload =
+            MessagePayload::parse_new("message=hi&delay=2h", "abcdef", "http://blah/c/abcdef")
+                .unwrap();
+        let delay = payload.deliver_at.unwrap() - Utc::now();
+        assert!(delay > chrono::Duration::minutes(119) && delay <= chrono::Duration::hours(2));
+
+        let tomorrow = Utc::now() + chrono::Duration::days(1);
+        let message = serde_json::json!({"message": "hi", "deliver_at": tomorrow});
+        let payload =
+            MessagePayload::parse_json(&message.to_string(), "abcdef", "http://blah/c/abcdef")
+                .unwrap();
+        assert_eq!(Some(tomorrow), payload.deliver_at);
+
+        let later = Utc::now() + chrono::Duration::days(31);
+        let message = serde_json::json!({"message": "hi", "deliver_at": later});
+        let payload =
+            MessagePayload::parse_json(&message.to_string(), "abcdef", "http://blah/c/abcdef");
+        assert!(payload.unwrap_err().errors[0].contains("30 days"));
+
+        let payload = MessagePayload::parse_json(
+            r#"{"message": "hi", "deliver_at": "2021-01-01T09:00:00Z", "delay": 60}"#,
+            "abcdef",
+            "http://blah/c/abcdef",
+        );
+        assert!(payload.unwrap_err().errors[0].starts_with("delay"));
+
+        let payload = MessagePayload::parse_json(
+            r#"{"message": "hi", "delay": 18446744073709551615}"#,
+            "abcdef",
+            "http://blah/c/abcdef",
+        );
+        assert!(payload.is_err());
+
+        let payload = MessagePayload::parse_json(
+            r#"{"message": "hi", "delay": "2147483647d"}"#,
+            "abcdef",
+            "http://blah/c/abcdef",
+        );
+        assert!(payload.unwrap_err().errors[0].contains("30 days"));
+    }
+
+    #[test]
+    pub fn test_stored_payload() {
+        let payload = MessagePayload::parse_json(
+            r#"{"title": "Backup", "body": "Check it", "tag": "backup", "ttl": 60, "topic": "backup"}"#,
+            "abcdef",
+            "http://blah/c/abcdef",
+        )
+        .unwrap();
+
+        let stored = MessagePayload::from_stored(&payload.to_stored().unwrap()).unwrap();
+        assert_eq!(payload, stored);
+    }
+
+    #[test]
+    pub fn test_parse_json_message() {
+        let payload = MessagePayload::parse_json(
+            r#"{"message": "build failed", "action": "https://ci.example.com/", "urgency": "high"}"#,
+            "abcdef",
+            "http://blah/c/abcdef",
+        )
+        .unwrap();
+
+        assert_eq!("build failed", payload.message);
+        assert_eq!("https://ci.example.com/", payload.data.action);
+        assert_eq!(Some(Urgency::High), payload.options.urgency);
+    }
+
+    #[test]
+    pub fn test_parse_invalid_message() {
+        let result = MessagePayload::parse_json(
+            r#"{"message": "", "action": "javascript:alert(1)", "topic": "not a topic"}"#,
+            "abcdef",
+            "http://blah/c/abcdef",
+        );
+        assert_eq!(3, result.unwrap_err().errors.len());
+
+        let result = MessagePayload::parse_json(
+            r#"{"message": "hi", "urgency": "asap"}"#,
+            "abcdef",
+            "http://blah/c/abcdef",
+        );
+        assert!(result.unwrap_err().errors[0].contains("asap"));
+
+        let result =
+            MessagePayload::parse_new("message=hi&ttl=soon", "abcdef", "http://blah/c/abcdef");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    pub fn test_classify_send_failure() {
+        assert!(matches!(
+            SendFailure::classify(&WebPushError::EndpointNotValid.into()),
+            SendFailure::Expired(_)
+        ));
+        assert!(matches!(
+            SendFailure::classify(&WebPushError::EndpointNotFound.into()),
+            SendFailure::Expired(_)
+        ));
+        assert_eq!(
+            SendFailure::Transient(Some(Duration::from_secs(5))),
+            SendFailure::classify(&WebPushError::ServerError(Some(Duration::from_secs(5))).into())
+        );
+        assert_eq!(
+            SendFailure::Transient(None),
+            SendFailure::classify(&TooManyRequests { retry_after: None }.into())
+        );
+        assert_eq!(
+            SendFailure::Permanent,
+            SendFailure::classify(&WebPushError::InvalidCryptoKeys.into())
+        );
+    }
+
+    #[test]
+    pub fn test_parse_retry_after() {
+        assert_eq!(Some(Duration::from_secs(120)), parse_retry_after("120"));
+        assert_eq!(
+            Some(Duration::from_secs(0)),
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT")
+        );
+        assert_eq!(None, parse_retry_after("soon"));
+    }
 }